@@ -4,6 +4,10 @@ use clap::Parser;
 use clap::Subcommand;
 
 use clap_num::maybe_hex;
+use hacklet::dongle::DongleSelector;
+
+use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Parser)]
 #[command(arg_required_else_help = true)]
@@ -14,6 +18,122 @@ pub struct Command {
     /// Enable debug messages (add this twice for trace level)
     #[arg(short, long, global=true, action = ArgAction::Count)]
     pub debug: u8,
+
+    /// Which dongle to use: an index or serial number from list-dongles, or a tty path
+    #[arg(long, global=true)]
+    pub dongle: Option<DongleSelector>,
+
+    /// Talk to the dongle through a tty (e.g. /dev/ttyUSB0) instead of D2XX
+    // The conflict lives here since --port only exists with the tty feature.
+    #[cfg(feature = "tty")]
+    #[arg(short, long, global=true, conflicts_with = "dongle")]
+    pub port: Option<PathBuf>,
+
+    /// Write all serial traffic to a capture file
+    #[arg(long, global=true)]
+    pub record: Option<PathBuf>,
+
+    /// Talk to a built-in emulated dongle with a couple of Modlets instead of real hardware
+    #[arg(long, global=true)]
+    pub emulate: bool,
+
+    /// Where to keep the list of commissioned devices (default ~/.config/hacklet/devices.toml)
+    #[arg(long, global=true)]
+    pub registry: Option<PathBuf>,
+
+    /// Offset of Modlet clocks from UTC, e.g. +02:00, or "local" for this host's offset (default UTC)
+    #[arg(long, global=true, allow_hyphen_values = true, value_parser = parse_utc_offset)]
+    pub utc_offset: Option<ClockOffset>,
+
+    #[command(flatten)]
+    pub serial: SerialArgs,
+}
+
+// Deadlines are the current time plus one of these, which has to stay in
+// range, and nothing we wait for takes longer than a day.
+const MAX_SECONDS: f64 = 24.0 * 60.0 * 60.0;
+
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    let seconds: f64 = s.parse().map_err(|_| format!("{:?} is not a number of seconds", s))?;
+    if seconds > MAX_SECONDS {
+        return Err(format!("{} seconds is more than a day", s));
+    }
+    Duration::try_from_secs_f64(seconds).map_err(|err| err.to_string())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClockOffset {
+    // Whatever this host's time zone says at the time
+    Local,
+    Seconds(i32),
+}
+
+// Takes "local", "UTC", "Z", or a signed offset in hours such as "+2" or
+// "-05:30", within the -12:00 to +14:00 that time zones actually use.
+fn parse_utc_offset(s: &str) -> Result<ClockOffset, String> {
+    let invalid = || format!("{:?} is not a UTC offset like +02:00", s);
+    if s.eq_ignore_ascii_case("local") {
+        return Ok(ClockOffset::Local);
+    }
+    if s.eq_ignore_ascii_case("utc") || s.eq_ignore_ascii_case("z") {
+        return Ok(ClockOffset::Seconds(0));
+    }
+    let (sign, offset) = match s.split_at_checked(1) {
+        Some(("+", offset)) => (1, offset),
+        Some(("-", offset)) => (-1, offset),
+        _ => return Err(invalid()),
+    };
+    // Plain digits only, since parse would also take a second sign.
+    let number = |part: &str| -> Result<i32, String> {
+        if part.is_empty() || part.len() > 2 || !part.bytes().all(|byte| byte.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        part.parse().map_err(|_| invalid())
+    };
+    let (hours, minutes) = match offset.split_once(':') {
+        Some((hours, minutes)) => (number(hours)?, number(minutes)?),
+        None => (number(offset)?, 0),
+    };
+    if minutes >= 60 {
+        return Err(invalid());
+    }
+    let total_minutes = sign * (hours * 60 + minutes);
+    if !(-12 * 60..=14 * 60).contains(&total_minutes) {
+        return Err(format!("{} is outside the -12:00 to +14:00 of any time zone", s));
+    }
+    Ok(ClockOffset::Seconds(total_minutes * 60))
+}
+
+#[derive(Args)]
+#[command(next_help_heading = "Serial options")]
+pub struct SerialArgs {
+    /// USB vendor ID of the dongle (default 0x0403)
+    #[arg(long, global=true, value_parser = maybe_hex::<u16>)]
+    pub vid: Option<u16>,
+
+    /// USB product ID of the dongle (default 0x8c81)
+    #[arg(long, global=true, value_parser = maybe_hex::<u16>)]
+    pub pid: Option<u16>,
+
+    /// Serial baud rate (default 115200)
+    #[arg(long, global=true)]
+    pub baud: Option<u32>,
+
+    /// D2XX write timeout in seconds (default 5)
+    #[arg(long, global=true, value_parser = parse_seconds)]
+    pub write_timeout: Option<Duration>,
+
+    /// How long to wait for the answer to each request, in seconds (default 10)
+    #[arg(long, global=true, value_parser = parse_seconds)]
+    pub response_timeout: Option<Duration>,
+
+    /// Keep whatever is already in the receive buffer when opening the dongle
+    #[arg(long, global=true)]
+    pub no_purge: bool,
+
+    /// Skip the boot handshake, e.g. when the dongle is already running
+    #[arg(long, global=true)]
+    pub no_boot: bool,
 }
 
 #[derive(Subcommand)]
@@ -27,8 +147,35 @@ pub enum Subcommands {
     /// Read all available samples from the specified socket
     Read(SocketArgs),
 
-    /// Add a new device to the network
-    Commission,
+    /// Add new devices to the network
+    Commission(CommissionArgs),
+
+    /// List the devices commissioned from this host
+    Devices,
+
+    /// Remove a device from the local registry (the Modlet itself keeps its pairing)
+    Forget(ForgetArgs),
+
+    /// Set the clock of one or all registered devices to the current time
+    SyncTime(SyncTimeArgs),
+
+    /// Program a weekly on/off schedule into the specified socket
+    Schedule(ScheduleArgs),
+
+    /// Dissect hex strings, trace logs or capture files into messages
+    Decode(DecodeArgs),
+
+    /// Send an arbitrary message and show everything that comes back
+    Raw(RawArgs),
+
+    /// Print every message the dongle sends until interrupted
+    Sniff(SniffArgs),
+
+    /// Show what the dongle reports about itself (only the device ID is decoded)
+    Info,
+
+    /// List connected FTDI devices, including Modlet dongles
+    ListDongles,
 }
 
 #[derive(Args)]
@@ -40,4 +187,137 @@ pub struct SocketArgs {
     /// The socket number, either 0 or 1 for top or bottom outlet
     #[arg(short, long, value_parser = clap::value_parser!(u8).range(0..2))]
     pub socket: u8,
-}
\ No newline at end of file
+}
+
+#[derive(Args)]
+pub struct CommissionArgs {
+    /// Stop after this many devices have been added
+    #[arg(short, long, default_value_t = 1)]
+    pub count: usize,
+
+    /// How long to keep the network open, in seconds
+    #[arg(short, long, default_value = "30", value_parser = parse_seconds)]
+    pub timeout: Duration,
+
+    /// Add every device that shows up without asking (the default when stdin is not a terminal)
+    #[arg(short, long)]
+    pub yes: bool,
+}
+
+#[derive(Args)]
+pub struct ForgetArgs {
+    /// The network ID of the device, (e.g. 0x215a)
+    #[arg(short, long, value_parser = maybe_hex::<u16>)]
+    pub network: u16,
+}
+
+#[derive(Args)]
+#[group(required = true, multiple = false)]
+pub struct SyncTimeArgs {
+    /// The network ID, (e.g. 0x215a)
+    #[arg(short, long, value_parser = maybe_hex::<u16>)]
+    pub network: Option<u16>,
+
+    /// Every device in the registry
+    #[arg(short, long)]
+    pub all: bool,
+}
+
+#[derive(Args)]
+pub struct ScheduleArgs {
+    #[command(flatten)]
+    pub socket: SocketArgs,
+
+    /// Days and on times, e.g. "Mon-Fri 07:00-09:00,18:00-23:00; Sat-Sun 09:00-23:30"
+    #[arg(required_unless_present = "from_file", conflicts_with = "from_file")]
+    pub program: Option<String>,
+
+    /// Read the weekly program from a TOML or YAML file
+    #[arg(short, long)]
+    pub from_file: Option<PathBuf>,
+
+    /// Show the schedule without sending it to the device
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Send a program other than always on or always off, whose encoding is unconfirmed
+    #[arg(long)]
+    pub experimental: bool,
+}
+
+#[derive(Args)]
+pub struct DecodeArgs {
+    /// Hex strings, or files containing trace logs or captures ("-" or nothing for stdin)
+    pub inputs: Vec<String>,
+}
+
+fn parse_command(s: &str) -> Result<u16, String> {
+    u16::from_str_radix(s.trim_start_matches("0x"), 16).map_err(|err| err.to_string())
+}
+
+#[derive(Args)]
+pub struct RawArgs {
+    /// The command ID in hex, e.g. 4003
+    #[arg(value_parser = parse_command)]
+    pub command: u16,
+
+    /// The payload in hex, e.g. 00010500
+    #[arg(default_value = "")]
+    pub payload: String,
+
+    /// How long to listen for replies, in seconds
+    #[arg(short, long, default_value = "2", value_parser = parse_seconds)]
+    pub wait: Duration,
+}
+
+#[derive(Args)]
+pub struct SniffArgs {
+    /// Also save all traffic to a capture file
+    #[arg(short, long)]
+    pub save: Option<PathBuf>,
+}
+
+#[cfg(test)]
+mod test_command {
+    use super::*;
+
+    #[cfg(feature = "tty")]
+    #[test]
+    fn test_port_conflicts_with_dongle() {
+        let parsed = Command::try_parse_from(["hacklet-rs", "--port", "/dev/ttyUSB0", "--dongle", "0", "info"]);
+        assert_eq!(parsed.err().map(|err| err.kind()), Some(clap::error::ErrorKind::ArgumentConflict));
+        assert!(Command::try_parse_from(["hacklet-rs", "--port", "/dev/ttyUSB0", "info"]).is_ok());
+    }
+
+    #[test]
+    fn test_parse_seconds() {
+        assert_eq!(parse_seconds("2"), Ok(Duration::from_secs(2)));
+        assert_eq!(parse_seconds("0.25"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_seconds("86400"), Ok(Duration::from_secs(86400)));
+        assert!(parse_seconds("86401").is_err());
+        assert!(parse_seconds("1e19").is_err());
+        assert!(parse_seconds("inf").is_err());
+        assert!(parse_seconds("NaN").is_err());
+        assert!(parse_seconds("-1").is_err());
+        assert!(parse_seconds("soon").is_err());
+    }
+
+    #[test]
+    fn test_parse_utc_offset() {
+        assert_eq!(parse_utc_offset("local"), Ok(ClockOffset::Local));
+        assert_eq!(parse_utc_offset("UTC"), Ok(ClockOffset::Seconds(0)));
+        assert_eq!(parse_utc_offset("z"), Ok(ClockOffset::Seconds(0)));
+        assert_eq!(parse_utc_offset("+2"), Ok(ClockOffset::Seconds(2 * 3600)));
+        assert_eq!(parse_utc_offset("-05"), Ok(ClockOffset::Seconds(-5 * 3600)));
+        assert_eq!(parse_utc_offset("+05:45"), Ok(ClockOffset::Seconds(5 * 3600 + 45 * 60)));
+        assert_eq!(parse_utc_offset("-09:30"), Ok(ClockOffset::Seconds(-(9 * 3600 + 30 * 60))));
+        assert_eq!(parse_utc_offset("+14:00"), Ok(ClockOffset::Seconds(14 * 3600)));
+        assert_eq!(parse_utc_offset("-12:00"), Ok(ClockOffset::Seconds(-12 * 3600)));
+        assert_eq!(parse_utc_offset("-00:00"), Ok(ClockOffset::Seconds(0)));
+
+        for invalid in ["", "2", "+", "++5", "+-5", "-+5", "-05:-30", "+05:", "+:30", "+005", "+05:3x",
+                        "+05:60", "+14:59", "+15", "-12:30", "-13", "+ 5", "+05:30:00"] {
+            assert!(parse_utc_offset(invalid).is_err(), "{:?} should not parse", invalid);
+        }
+    }
+}
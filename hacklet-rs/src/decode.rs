@@ -0,0 +1,342 @@
+use log::debug;
+use std::io::Read;
+use std::path::Path;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use hacklet::capture::{CaptureEntry, Direction};
+use hacklet::dongle::DongleError;
+use hacklet::messages::{message_checksum, Frame, MESSAGE_HEADER_LENGTH, MESSAGE_MAGIC};
+use hacklet::power::SampleBatch;
+use hacklet::schedule::WeeklySchedule;
+
+use crate::format_time;
+
+// A run of bytes from one input line, and which way it went if the input
+// says so. Bytes are None where a capture has a ?? wildcard.
+pub struct Chunk {
+    pub direction: Option<Direction>,
+    pub bytes: Vec<Option<u8>>,
+}
+
+// Accepts hex in most of the shapes it gets pasted in: "02 40 04 00 44",
+// "0240040044", "0x02, 0x40" or the "[2, 40, 4, 0, 44]" of our trace logs.
+pub fn parse_hex(s: &str) -> Result<Vec<u8>, DongleError> {
+    let invalid = || DongleError::InvalidInput(format!("{:?} is not a hex string", s));
+    let mut bytes = Vec::new();
+    for token in s.split(|c: char| c.is_whitespace() || ",:[]".contains(c)).filter(|token| !token.is_empty()) {
+        let token = token.trim_start_matches("0x");
+        if token.is_empty() || token.len() > 2 && token.len() % 2 != 0 {
+            return Err(invalid());
+        }
+        if token.len() <= 2 {
+            bytes.push(u8::from_str_radix(token, 16).map_err(|_| invalid())?);
+            continue;
+        }
+        for pair in token.as_bytes().chunks(2) {
+            let pair = std::str::from_utf8(pair).map_err(|_| invalid())?;
+            bytes.push(u8::from_str_radix(pair, 16).map_err(|_| invalid())?);
+        }
+    }
+    Ok(bytes)
+}
+
+// Reads capture files and trace logs line by line. Anything else on a line
+// is taken as plain hex, and lines that aren't hex either (such as other log
+// messages) are skipped.
+pub fn parse_text(text: &str) -> Vec<Chunk> {
+    let mut chunks = Vec::new();
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+        if let Ok(entry) = line.parse::<CaptureEntry>() {
+            chunks.push(Chunk { direction: Some(entry.direction), bytes: entry.data });
+            continue;
+        }
+
+        let traced = [("TX: ", Direction::Tx), ("RX: ", Direction::Rx)].into_iter()
+            .find_map(|(marker, direction)| line.find(marker).map(|start| (direction, &line[start + marker.len()..])));
+        let chunk = match traced {
+            Some((direction, bytes)) => parse_hex(bytes).map(|bytes| Chunk { direction: Some(direction), bytes: known(bytes) }),
+            None => parse_hex(line).map(|bytes| Chunk { direction: None, bytes: known(bytes) }),
+        };
+        match chunk {
+            Ok(chunk) => chunks.push(chunk),
+            Err(_) => debug!("Skipping {:?}", line),
+        }
+    }
+    chunks
+}
+
+pub fn read_input(input: &str) -> Result<Vec<Chunk>, DongleError> {
+    if input == "-" {
+        let mut text = String::new();
+        std::io::stdin().read_to_string(&mut text)?;
+        return Ok(parse_text(&text));
+    }
+    let path = Path::new(input);
+    if path.is_file() {
+        return Ok(parse_text(&std::fs::read_to_string(path)?));
+    }
+    Ok(vec![Chunk { direction: None, bytes: known(parse_hex(input)?) }])
+}
+
+fn known(bytes: Vec<u8>) -> Vec<Option<u8>> {
+    bytes.into_iter().map(Some).collect()
+}
+
+#[derive(Debug, PartialEq)]
+enum Piece {
+    Frame(Vec<Option<u8>>),
+    // Bytes that can't be the start of a frame
+    Garbage(Vec<Option<u8>>),
+    // A frame that ran past the end of the input
+    Truncated(Vec<Option<u8>>),
+}
+
+// Like FrameReader, but keeps what it can't make sense of so it can be shown.
+// Frames with a bad checksum are kept whole rather than resynchronised,
+// since when reading a dump it is more useful to see what arrived.
+fn split_frames(buffer: &mut Vec<Option<u8>>, finished: bool) -> Vec<Piece> {
+    let mut pieces = Vec::new();
+    loop {
+        match buffer.iter().position(|byte| *byte == Some(MESSAGE_MAGIC)) {
+            Some(0) => (),
+            Some(start) => pieces.push(Piece::Garbage(buffer.drain(..start).collect())),
+            None if buffer.is_empty() => break,
+            None => {
+                pieces.push(Piece::Garbage(std::mem::take(buffer)));
+                break;
+            },
+        }
+        if buffer.len() >= MESSAGE_HEADER_LENGTH && buffer[3].is_none() {
+            // Without the length there is no telling where the frame ends.
+            pieces.push(Piece::Garbage(buffer.drain(..1).collect()));
+            continue;
+        }
+        let payload_length = buffer.get(3).copied().flatten().unwrap_or(0) as usize;
+        let complete = buffer.len() >= MESSAGE_HEADER_LENGTH &&
+            buffer.len() > MESSAGE_HEADER_LENGTH + payload_length;
+        if !complete {
+            if finished {
+                pieces.push(Piece::Truncated(std::mem::take(buffer)));
+            }
+            break;
+        }
+        let frame_length = MESSAGE_HEADER_LENGTH + payload_length + 1;
+        pieces.push(Piece::Frame(buffer.drain(..frame_length).collect()));
+    }
+    pieces
+}
+
+fn label(direction: Option<Direction>) -> &'static str {
+    match direction {
+        Some(Direction::Tx) => "TX",
+        Some(Direction::Rx) => "RX",
+        None => "--",
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<String>>().join(" ")
+}
+
+// Like hex, with ?? for wildcard bytes.
+fn pattern(bytes: &[Option<u8>]) -> String {
+    bytes.iter()
+        .map(|byte| byte.map_or("??".to_string(), |byte| format!("{:02x}", byte)))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn describe(frame: &Frame) -> Vec<String> {
+    match frame {
+        Frame::BootResponse(message) => vec![
+            format!("device ID: 0x{:016x}", message.device_id),
+            format!("data: {}", hex(&message.data)),
+            format!("data2: 0x{:04x}", message.data2),
+        ],
+        Frame::BroadcastResponse(message) => vec![
+            format!("network ID: 0x{:04x}", message.network_id),
+            format!("device ID: 0x{:016x}", message.device_id),
+            format!("data: 0x{:02x}", message.data),
+        ],
+        Frame::UpdateTimeResponse(message) => vec![format!("network ID: 0x{:04x}", message.network_id)],
+        Frame::HandshakeRequest(message) => vec![format!("network ID: 0x{:04x}", message.network_id)],
+        Frame::UpdateTimeRequest(message) => vec![
+            format!("network ID: 0x{:04x}", message.network_id),
+            format!("time: {}", format_time(SystemTime::UNIX_EPOCH + Duration::from_secs(message.time as u64))),
+        ],
+        Frame::SamplesRequest(message) => vec![
+            format!("network ID: 0x{:04x}", message.network_id),
+            format!("channel: {}", message.channel_id),
+        ],
+        Frame::SamplesResponse(message) => {
+            let batch = SampleBatch::from(message);
+            let mut lines = vec![
+                format!("network ID: 0x{:04x}", batch.network_id),
+                format!("channel: {}", batch.channel_id),
+                format!("data: 0x{:04x}", message.data),
+                format!("stored samples: {}", batch.stored_sample_count),
+            ];
+            for reading in batch.readings {
+                lines.push(format!("{}: {} ({:.1}W)", format_time(reading.timestamp), reading.raw, reading.watts));
+            }
+            lines
+        },
+        Frame::ScheduleRequest(message) => {
+            let mut lines = vec![
+                format!("network ID: 0x{:04x}", message.network_id),
+                format!("channel: {}", message.channel_id),
+            ];
+            lines.extend(WeeklySchedule::decode(&message.schedule).render_grid().lines().map(str::to_string));
+            lines
+        },
+        Frame::Unknown { payload, .. } => vec![format!("payload: {}", hex(payload))],
+        _ => vec![],
+    }
+}
+
+// Prints one whole frame: a header line with the message name, followed by
+// its fields indented underneath.
+pub fn print_frame(prefix: &str, direction: Option<Direction>, buf: &[u8]) {
+    let command = u16::from_be_bytes([buf[1], buf[2]]);
+    let payload = &buf[MESSAGE_HEADER_LENGTH..buf.len() - 1];
+    let header = format!("{}{} 0x{:04x}", prefix, label(direction), command);
+
+    let expected = message_checksum(&buf[1..buf.len() - 1]);
+    let actual = buf[buf.len() - 1];
+    if expected != actual {
+        println!("{} ({} byte payload) checksum 0x{:02x} does not match calculated 0x{:02x}", header, payload.len(), actual, expected);
+        println!("    payload: {}", hex(payload));
+        return;
+    }
+
+    match Frame::decode(buf) {
+        Ok(frame) => {
+            println!("{} {} ({} byte payload)", header, frame.name(), payload.len());
+            for line in describe(&frame) {
+                println!("    {}", line);
+            }
+        },
+        Err(err) => println!("{} undecodable: {}", header, err),
+    }
+}
+
+// A captured frame with wildcards can't be checksummed or decoded, so only
+// its header and payload are shown.
+fn print_pattern(direction: Option<Direction>, buf: &[Option<u8>]) {
+    let command = match (buf[1], buf[2]) {
+        (Some(high), Some(low)) => format!("0x{:04x}", u16::from_be_bytes([high, low])),
+        _ => "0x????".to_string(),
+    };
+    let payload = &buf[MESSAGE_HEADER_LENGTH..buf.len() - 1];
+    println!("{} {} ({} byte payload) has wildcard bytes, not decoded", label(direction), command, payload.len());
+    println!("    payload: {}", pattern(payload));
+}
+
+// Splits each direction into frames separately, since TX and RX chunks are
+// interleaved but each is its own stream.
+pub fn decode(chunks: Vec<Chunk>) {
+    let mut buffers: [(Option<Direction>, Vec<Option<u8>>); 3] = [
+        (Some(Direction::Tx), Vec::new()),
+        (Some(Direction::Rx), Vec::new()),
+        (None, Vec::new()),
+    ];
+    let print_pieces = |direction: Option<Direction>, pieces: Vec<Piece>| {
+        for piece in pieces {
+            match piece {
+                Piece::Frame(buf) => match buf.iter().copied().collect::<Option<Vec<u8>>>() {
+                    Some(buf) => print_frame("", direction, &buf),
+                    None => print_pattern(direction, &buf),
+                },
+                Piece::Garbage(bytes) => println!("{} {} bytes outside any frame: {}", label(direction), bytes.len(), pattern(&bytes)),
+                Piece::Truncated(bytes) => println!("{} truncated frame: {}", label(direction), pattern(&bytes)),
+            }
+        }
+    };
+
+    for chunk in chunks {
+        let (direction, buffer) = buffers.iter_mut()
+            .find(|(direction, _)| *direction == chunk.direction)
+            .unwrap();
+        buffer.extend(chunk.bytes);
+        print_pieces(*direction, split_frames(buffer, false));
+    }
+    for (direction, buffer) in buffers.iter_mut() {
+        print_pieces(*direction, split_frames(buffer, true));
+    }
+}
+
+#[cfg(test)]
+mod test_decode {
+    use super::*;
+
+    const LOCK_RESPONSE: [u8; 6] = [0x02, 0xa0, 0xf9, 0x01, 0x00, 0x58];
+
+    #[test]
+    fn test_parse_hex() {
+        let expected = vec![0x02, 0x40, 0x04, 0x00, 0x44];
+        assert_eq!(parse_hex("02 40 04 00 44").unwrap(), expected);
+        assert_eq!(parse_hex("0240040044").unwrap(), expected);
+        assert_eq!(parse_hex("0x02, 0x40, 0x04, 0x00, 0x44").unwrap(), expected);
+        assert_eq!(parse_hex("[2, 40, 4, 0, 44]").unwrap(), expected);
+        assert_eq!(parse_hex("").unwrap(), vec![]);
+
+        assert!(parse_hex("024").is_err());
+        assert!(parse_hex("02 4g").is_err());
+        assert!(parse_hex("0x").is_err());
+    }
+
+    #[test]
+    fn test_parse_text() {
+        let text = "# hacklet capture\n\
+                    0.000010 TX 02 40 22 06 3b 1c ?? ?? ?? ?? ??\n\
+                    2026-10-16T20:15:30Z TRACE [hacklet::tty_connection] RX: [2, a0, f9, 1, 0, 58]\n\
+                    Opening tty \"/dev/ttyUSB0\"\n\
+                    02 40 04 00 44\n";
+        let chunks = parse_text(text);
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0].direction, Some(Direction::Tx));
+        assert_eq!(chunks[0].bytes[..6], known(vec![0x02, 0x40, 0x22, 0x06, 0x3b, 0x1c])[..]);
+        assert_eq!(chunks[0].bytes[6..], [None; 5]);
+        assert_eq!(chunks[1].direction, Some(Direction::Rx));
+        assert_eq!(chunks[1].bytes, known(LOCK_RESPONSE.to_vec()));
+        assert_eq!(chunks[2].direction, None);
+        assert_eq!(chunks[2].bytes, known(vec![0x02, 0x40, 0x04, 0x00, 0x44]));
+    }
+
+    #[test]
+    fn test_split_frames() {
+        let mut buffer = known(vec![0xff, 0x13]);
+        buffer.extend(known(LOCK_RESPONSE.to_vec()));
+        buffer.extend(known(LOCK_RESPONSE[..3].to_vec()));
+        assert_eq!(split_frames(&mut buffer, false), vec![
+            Piece::Garbage(known(vec![0xff, 0x13])),
+            Piece::Frame(known(LOCK_RESPONSE.to_vec())),
+        ]);
+        assert_eq!(buffer, known(LOCK_RESPONSE[..3].to_vec()));
+
+        buffer.extend(known(LOCK_RESPONSE[3..].to_vec()));
+        buffer.extend(known(vec![0x02, 0x40, 0x03]));
+        assert_eq!(split_frames(&mut buffer, true), vec![
+            Piece::Frame(known(LOCK_RESPONSE.to_vec())),
+            Piece::Truncated(known(vec![0x02, 0x40, 0x03])),
+        ]);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_split_wildcard_frames() {
+        let mut buffer = vec![Some(0x02), Some(0x40), Some(0x22), Some(0x06), Some(0x3b), Some(0x1c), None, None, None, None, None];
+        let frame = buffer.clone();
+        assert_eq!(split_frames(&mut buffer, true), vec![Piece::Frame(frame)]);
+
+        // A wildcard length leaves nothing to go on but the next magic byte.
+        let mut buffer = vec![Some(0x02), Some(0x40), Some(0x22), None];
+        buffer.extend(known(LOCK_RESPONSE.to_vec()));
+        assert_eq!(split_frames(&mut buffer, true), vec![
+            Piece::Garbage(vec![Some(0x02)]),
+            Piece::Garbage(vec![Some(0x40), Some(0x22), None]),
+            Piece::Frame(known(LOCK_RESPONSE.to_vec())),
+        ]);
+    }
+}
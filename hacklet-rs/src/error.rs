@@ -0,0 +1,39 @@
+use std::fmt;
+
+use hacklet::dongle::DongleError;
+
+// Errors from the command line tool itself, on top of everything the library
+// can report.
+#[derive(Debug)]
+pub enum CliError {
+    Dongle(DongleError),
+    // The local device registry can't be used or doesn't have the device
+    Registry(String),
+    Interrupt(ctrlc::Error),
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CliError::Dongle(err) => write!(f, "{}", err),
+            CliError::Registry(reason) => write!(f, "device registry: {}", reason),
+            CliError::Interrupt(err) => write!(f, "could not handle Ctrl-C: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for CliError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CliError::Dongle(err) => Some(err),
+            CliError::Interrupt(err) => Some(err),
+            CliError::Registry(_) => None,
+        }
+    }
+}
+
+impl From<DongleError> for CliError {
+    fn from(err: DongleError) -> Self {
+        CliError::Dongle(err)
+    }
+}
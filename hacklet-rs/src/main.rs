@@ -1,11 +1,227 @@
 use clap::Parser;
-use log::info;
+use log::{error, info, warn};
+use std::io::IsTerminal;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use time::OffsetDateTime;
+use time::UtcOffset;
+use time::format_description::well_known::Rfc3339;
 
 mod command;
-use command::{Command, Subcommands};
-use hacklet::dongle::{Dongle, DongleError, SwitchState, CommissionStatus};
+mod decode;
+mod error;
+mod registry;
+mod schedule_file;
+use command::{ClockOffset, Command, Subcommands};
+use error::CliError;
+use registry::Registry;
+use hacklet::capture::{Direction, RecordingTransport};
+use hacklet::dongle::{CommissionDecision, Dongle, DongleBuilder, DongleError, DongleId, SwitchState};
+use hacklet::emulator::{Emulator, EmulatedModlet, EmulatedTransport, LoadProfile};
+use hacklet::messages::Frame;
+use hacklet::schedule::WeeklySchedule;
+#[cfg(feature = "d2xx")]
+use hacklet::serial_connection::SerialConnection;
+use hacklet::transport::SerialSettings;
+use hacklet::transport::Transport;
 
-fn main() -> Result<(), DongleError> {
+// Network 0x215a is already commissioned with a lamp and a fridge plugged in,
+// and 0x3b1c turns up as soon as the network is unlocked.
+fn demo_emulator() -> Emulator {
+    let mut modlet = EmulatedModlet::new(0x215a, 0x0b2f000000584f81);
+    modlet.outlets[0].load = LoadProfile::Constant(60.0);
+    modlet.outlets[1].load = LoadProfile::Cycle { low: 2.0, high: 120.0, period: Duration::from_secs(600) };
+
+    let mut emulator = Emulator::new(0x0b2f000000584f80);
+    emulator.add_modlet(modlet);
+    emulator.add_pending_modlet(EmulatedModlet::new(0x3b1c, 0x0b2f000000584f82));
+    emulator
+}
+
+fn dongle_builder(run: &Command) -> DongleBuilder {
+    let serial = &run.serial;
+    let defaults = SerialSettings::default();
+    let mut builder = DongleBuilder::new()
+        .vid_pid(serial.vid.unwrap_or(defaults.vendor_id), serial.pid.unwrap_or(defaults.product_id))
+        .baud_rate(serial.baud.unwrap_or(defaults.baud_rate))
+        .write_timeout(serial.write_timeout.unwrap_or(defaults.write_timeout))
+        .purge(!serial.no_purge)
+        .boot(!serial.no_boot)
+        .utc_offset(match run.utc_offset {
+            Some(ClockOffset::Local) => local_utc_offset(),
+            Some(ClockOffset::Seconds(seconds)) => seconds,
+            None => 0,
+        });
+    if let Some(timeout) = serial.response_timeout {
+        builder = builder.response_timeout(timeout);
+    }
+
+    #[cfg(feature = "tty")]
+    if let Some(port) = &run.port {
+        builder = builder.selector(hacklet::dongle::DongleSelector::Path(port.clone()));
+    }
+    if let Some(selector) = &run.dongle {
+        builder = builder.selector(selector.clone());
+    }
+    builder
+}
+
+fn open_dongle(run: &Command) -> Result<Dongle, DongleError> {
+    open_recording_dongle(run, run.record.as_deref())
+}
+
+fn open_recording_dongle(run: &Command, record: Option<&Path>) -> Result<Dongle, DongleError> {
+    let builder = dongle_builder(run);
+    let mut transport: Box<dyn Transport> = if run.emulate {
+        Box::new(EmulatedTransport::new(Arc::new(Mutex::new(demo_emulator()))))
+    } else {
+        builder.open_transport()?
+    };
+    if let Some(path) = record {
+        transport = Box::new(RecordingTransport::create(transport, path)?);
+    }
+    builder.with_transport(transport)
+}
+
+#[cfg(feature = "d2xx")]
+fn list_dongles(settings: &SerialSettings) -> Result<(), DongleError> {
+    let devices = SerialConnection::list_devices(settings)?;
+    if devices.is_empty() {
+        info!("No FTDI devices found");
+    }
+    let mut index = 0;
+    for device in devices {
+        let dongle = if SerialConnection::is_dongle(&device, settings) {
+            index += 1;
+            format!("{}", index - 1)
+        } else {
+            "-".to_string()
+        };
+        println!("{:>2}  {:<16} {:<24} {:04x}:{:04x}{}", dongle, device.serial_number, device.description,
+                 device.vendor_id, device.product_id, if device.port_open { "  (in use)" } else { "" });
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "d2xx"))]
+fn list_dongles(_settings: &SerialSettings) -> Result<(), DongleError> {
+    Err(DongleError::Unsupported("built without D2XX support, look for the dongle under /dev/serial/by-id".to_string()))
+}
+
+// Reads stdin on its own thread, so that waiting for an answer doesn't keep
+// Ctrl-C from stopping commissioning.
+fn read_answers() -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut answer = String::new();
+        while matches!(std::io::stdin().read_line(&mut answer), Ok(count) if count > 0) {
+            if sender.send(std::mem::take(&mut answer)).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+fn confirm_device(answers: &Receiver<String>, interrupted: &AtomicBool) -> CommissionDecision {
+    // The device has joined the network by now whatever the answer, so this
+    // only decides whether it is set up and recorded.
+    print!("It has already paired. Set its clock and record it? [y/N/q] ");
+    let _ = std::io::stdout().flush();
+    let answer = loop {
+        if interrupted.load(Ordering::SeqCst) {
+            println!();
+            return CommissionDecision::Stop;
+        }
+        match answers.recv_timeout(Duration::from_millis(250)) {
+            Ok(answer) => break answer,
+            Err(RecvTimeoutError::Timeout) => continue,
+            // End of input
+            Err(RecvTimeoutError::Disconnected) => {
+                println!();
+                return CommissionDecision::Stop;
+            },
+        }
+    };
+    match answer.trim().to_ascii_lowercase().as_str() {
+        "y" | "yes" => CommissionDecision::Accept,
+        "q" | "quit" => CommissionDecision::Stop,
+        _ => {
+            info!("Leaving it paired, but without setting its clock or recording it");
+            CommissionDecision::Reject
+        },
+    }
+}
+
+fn registry_path(run: &Command) -> Result<PathBuf, CliError> {
+    match &run.registry {
+        Some(path) => Ok(path.clone()),
+        None => registry::default_path(),
+    }
+}
+
+fn record_devices(run: &Command, ids: &[DongleId]) -> Result<(), CliError> {
+    let path = registry_path(run)?;
+    let mut registry = Registry::read(&path)?;
+    let now = format_time(SystemTime::now());
+    for id in ids {
+        registry.add(id, now.clone());
+    }
+    registry.write(&path)
+}
+
+// Needs to run before any other thread starts, or the local offset can't be
+// read safely and UTC is used instead.
+fn local_utc_offset() -> i32 {
+    match UtcOffset::current_local_offset() {
+        Ok(offset) => offset.whole_seconds(),
+        Err(err) => {
+            warn!("Using UTC for Modlet clocks: {}", err);
+            0
+        },
+    }
+}
+
+fn format_time(time: SystemTime) -> String {
+    OffsetDateTime::from(time).format(&Rfc3339).unwrap_or_else(|_| format!("{:?}", time))
+}
+
+// Commissioning ran its course without finding a device.
+const EXIT_NOT_COMMISSIONED: u8 = 5;
+
+// The device registry couldn't be used, or didn't have the device.
+const EXIT_REGISTRY: u8 = 6;
+
+fn exit_code(err: &CliError) -> u8 {
+    let err = match err {
+        CliError::Dongle(err) => err,
+        CliError::Registry(_) => return EXIT_REGISTRY,
+        CliError::Interrupt(_) => return 1,
+    };
+    match err {
+        DongleError::DeviceNotFound => 2,
+        DongleError::Timeout => 3,
+        DongleError::ChecksumMismatch { .. } |
+        DongleError::UnexpectedCommand { .. } |
+        DongleError::Truncated |
+        DongleError::MalformedMessage(_) => 4,
+        _ => 1,
+    }
+}
+
+fn main() -> ExitCode {
     let run = Command::parse();
 
     match run.debug {
@@ -14,33 +230,195 @@ fn main() -> Result<(), DongleError> {
         _ => simple_logger::init_with_level(log::Level::Info).unwrap(),
     }
 
+    match execute(&run) {
+        Ok(code) => code,
+        Err(err) => {
+            error!("{}", err);
+            ExitCode::from(exit_code(&err))
+        },
+    }
+}
+
+fn execute(run: &Command) -> Result<ExitCode, CliError> {
     match &run.command {
         Some(Subcommands::On(args)) => {
             info!("Turning on channel {:?} on network 0x{:x?}", args.socket, args.network);
-            let mut dongle = Dongle::open()?;
+            let mut dongle = open_dongle(run)?;
             dongle.switch(args.network, args.socket, SwitchState::AlwaysOn)?;
         },
         Some(Subcommands::Off(args)) => {
             info!("Turning off channel {:?} on network 0x{:x?}", args.socket, args.network);
-            let mut dongle = Dongle::open()?;
+            let mut dongle = open_dongle(run)?;
             dongle.switch(args.network, args.socket, SwitchState::AlwaysOff)?;
         },
         Some(Subcommands::Read(args)) => {
             info!("Reading power samples from device...");
-            let mut dongle = Dongle::open()?;
-            let response = dongle.request_samples(args.network, args.socket as u16)?;
-            info!("Samples: {:x?}", response);
-        },
-        Some(Subcommands::Commission) => {
-            info!("Listening for new device network...");
-            let mut dongle = Dongle::open()?;
-            let response = dongle.commission()?;
-            if let CommissionStatus::Commissioned(id) = response {
+            let mut dongle = open_dongle(run)?;
+            let history = dongle.read_history(args.network, args.socket as u16)?;
+            info!("Read {:?} samples", history.readings.len());
+            for gap in &history.gaps {
+                warn!("No samples between {} and {}", format_time(gap.after), format_time(gap.before));
+            }
+            for reading in history.readings {
+                println!("{}: {:.1}W", format_time(reading.timestamp), reading.watts);
+            }
+        },
+        Some(Subcommands::Commission(args)) => {
+            let mut dongle = open_dongle(run)?;
+            let interrupted = dongle.interrupt_flag();
+            ctrlc::set_handler(move || {
+                // A second Ctrl-C gives up on relocking the network, in case
+                // the dongle stopped answering.
+                if interrupted.swap(true, Ordering::SeqCst) {
+                    eprintln!("Exiting without relocking the network");
+                    std::process::exit(130);
+                }
+            }).map_err(CliError::Interrupt)?;
+
+            // Without a terminal to ask, add devices as the original did.
+            let answers = match args.yes || !std::io::stdin().is_terminal() {
+                true => None,
+                false => Some(read_answers()),
+            };
+            info!("Listening for new devices for {:?}, press Ctrl-C to stop...", args.timeout);
+            let interrupted = dongle.interrupt_flag();
+            let mut remaining = args.count;
+            let accepted = dongle.commission_many(args.timeout, |id| {
                 info!("Found device 0x{:x?} on network 0x{:x?}", id.device, id.network);
+                let decision = match &answers {
+                    Some(answers) => confirm_device(answers, &interrupted),
+                    None => CommissionDecision::Accept,
+                };
+                if interrupted.load(Ordering::SeqCst) {
+                    return CommissionDecision::Stop;
+                }
+                if decision == CommissionDecision::Accept {
+                    remaining = remaining.saturating_sub(1);
+                    if remaining == 0 {
+                        return CommissionDecision::AcceptAndStop;
+                    }
+                }
+                decision
+            })?;
+            if accepted.is_empty() {
+                warn!("No new device was added");
+                return Ok(ExitCode::from(EXIT_NOT_COMMISSIONED));
+            }
+            info!("Added {} device(s)", accepted.len());
+            for id in &accepted {
+                println!("0x{:04x} 0x{:016x}", id.network, id.device);
+            }
+            // The devices are paired either way, so don't fail over this.
+            // Emulated devices don't belong in the registry at all.
+            if run.emulate {
+                info!("Not recording emulated devices");
+            } else if let Err(err) = record_devices(run, &accepted) {
+                warn!("Could not record the new devices: {}", err);
             }
         },
+        Some(Subcommands::Devices) => {
+            let registry = Registry::read(&registry_path(run)?)?;
+            for device in &registry.devices {
+                println!("0x{:04x} 0x{:016x} {} {}", device.network, device.device,
+                         device.commissioned.as_deref().unwrap_or("-"), device.name.as_deref().unwrap_or(""));
+            }
+        },
+        Some(Subcommands::Forget(args)) => {
+            let path = registry_path(run)?;
+            let mut registry = Registry::read(&path)?;
+            let device = registry.forget(args.network)
+                .ok_or_else(|| CliError::Registry(format!("network 0x{:04x} is not in {}", args.network, path.display())))?;
+            registry.write(&path)?;
+            info!("Forgot device 0x{:016x} on network 0x{:04x}", device.device, device.network);
+            warn!("The Modlet is still paired with the dongle, there is no known message to undo commissioning");
+        },
+        Some(Subcommands::SyncTime(args)) => {
+            let networks: Vec<u16> = match args.network {
+                Some(network) => vec![network],
+                None => Registry::read(&registry_path(run)?)?.devices.iter().map(|device| device.network).collect(),
+            };
+            if networks.is_empty() {
+                return Err(CliError::Registry("no devices to sync, commission one or use --network".to_string()));
+            }
+            let mut dongle = open_dongle(run)?;
+            let mut failed = None;
+            for network in networks {
+                match dongle.sync_time(network) {
+                    Ok(_) => info!("Set the clock on network 0x{:04x}", network),
+                    Err(err) => {
+                        warn!("Could not set the clock on network 0x{:04x}: {}", network, err);
+                        failed = Some(err);
+                    },
+                }
+            }
+            if let Some(err) = failed {
+                return Err(err.into());
+            }
+        },
+        Some(Subcommands::Schedule(args)) => {
+            let schedule = match (&args.program, &args.from_file) {
+                (_, Some(path)) => schedule_file::read_schedule_file(path)?,
+                (Some(program), None) => program.parse::<WeeklySchedule>()?,
+                (None, None) => unreachable!("clap requires a program or a file"),
+            };
+            // Make sure the schedule fits before showing it.
+            schedule.encode()?;
+            info!("Schedule for channel {:?} on network 0x{:x?}:", args.socket.socket, args.socket.network);
+            print!("{}", schedule.render_grid());
+            if args.dry_run {
+                return Ok(ExitCode::SUCCESS);
+            }
+            if !schedule.is_constant() {
+                if !args.experimental {
+                    return Err(DongleError::InvalidInput(
+                        "only always on and always off programs are known to encode correctly, \
+                         pass --experimental to send this one anyway".to_string()).into());
+                }
+                warn!("Sending a program with an unconfirmed encoding, check the outlet follows it");
+            }
+            let mut dongle = open_dongle(run)?;
+            dongle.set_schedule(args.socket.network, args.socket.socket, &schedule)?;
+        },
+        Some(Subcommands::Decode(args)) => {
+            let inputs = if args.inputs.is_empty() { vec!["-".to_string()] } else { args.inputs.clone() };
+            let mut chunks = Vec::new();
+            for input in &inputs {
+                chunks.extend(decode::read_input(input)?);
+            }
+            decode::decode(chunks);
+        },
+        Some(Subcommands::Raw(args)) => {
+            let frame = Frame::Unknown { command: args.command, payload: decode::parse_hex(&args.payload)? };
+            let mut dongle = open_dongle(run)?;
+            decode::print_frame("", Some(Direction::Tx), &frame.encode()?);
+            dongle.send_frame(&frame)?;
+
+            let deadline = Instant::now() + args.wait;
+            while let Some(frame) = dongle.wait_event(deadline.saturating_duration_since(Instant::now()))? {
+                decode::print_frame("", Some(Direction::Rx), &frame.encode()?);
+            }
+        },
+        Some(Subcommands::Sniff(args)) => {
+            let mut dongle = open_recording_dongle(run, args.save.as_deref().or(run.record.as_deref()))?;
+            info!("Listening for messages, press Ctrl-C to stop...");
+            loop {
+                if let Some(frame) = dongle.wait_event(Duration::from_secs(1))? {
+                    let prefix = format!("{} ", format_time(SystemTime::now()));
+                    decode::print_frame(&prefix, Some(Direction::Rx), &frame.encode()?);
+                }
+            }
+        },
+        Some(Subcommands::Info) => {
+            let dongle = open_dongle(run)?;
+            let info = dongle.info()
+                .ok_or_else(|| DongleError::Unsupported("the dongle only identifies itself during the boot handshake".to_string()))?;
+            println!("Device ID: 0x{:016x}", info.device_id);
+            println!("Undecoded boot data: {}", decode::hex(&info.raw_boot_data));
+            println!("Undecoded boot data2: 0x{:04x}", info.raw_boot_data2);
+        },
+        Some(Subcommands::ListDongles) => list_dongles(dongle_builder(run).settings())?,
         _ => {}
     };
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
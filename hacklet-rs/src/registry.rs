@@ -0,0 +1,169 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::path::Path;
+use std::path::PathBuf;
+
+use hacklet::dongle::DongleId;
+
+use crate::error::CliError;
+
+// What we remember about the devices commissioned from this host. The Modlets
+// and the dongle keep their own pairing, which there is no known message to
+// undo, so this is only a local record:
+//
+//   [[devices]]
+//   network = "0x215a"
+//   device = "0x0b2f000000584f82"
+//   name = "Living room"
+//   commissioned = "2026-10-16T20:15:30Z"
+//
+// Names are not set by hacklet-rs, but survive being edited in by hand.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Registry {
+    #[serde(default)]
+    pub devices: Vec<RegisteredDevice>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RegisteredDevice {
+    #[serde(serialize_with = "write_network", deserialize_with = "read_network")]
+    pub network: u16,
+    // Device IDs don't fit in a TOML integer, so both IDs are hex strings.
+    #[serde(serialize_with = "write_device", deserialize_with = "read_device")]
+    pub device: u64,
+    pub name: Option<String>,
+    pub commissioned: Option<String>,
+}
+
+fn write_network<S: Serializer>(network: &u16, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format!("0x{:04x}", network))
+}
+
+fn write_device<S: Serializer>(device: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format!("0x{:016x}", device))
+}
+
+fn read_hex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    u64::from_str_radix(s.trim_start_matches("0x"), 16)
+        .map_err(|_| serde::de::Error::custom(format!("{:?} is not a hex ID", s)))
+}
+
+fn read_network<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u16, D::Error> {
+    let id = read_hex(deserializer)?;
+    u16::try_from(id).map_err(|_| serde::de::Error::custom(format!("network ID 0x{:x} is too large", id)))
+}
+
+fn read_device<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    read_hex(deserializer)
+}
+
+// $XDG_CONFIG_HOME/hacklet/devices.toml, falling back to ~/.config.
+pub fn default_path() -> Result<PathBuf, CliError> {
+    let config = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .ok_or_else(|| CliError::Registry("no HOME to keep it in, use --registry".to_string()))?;
+    Ok(config.join("hacklet").join("devices.toml"))
+}
+
+impl Registry {
+    // A registry that doesn't exist yet is just empty.
+    pub fn read(path: &Path) -> Result<Registry, CliError> {
+        let failed = |err: String| CliError::Registry(format!("{}: {}", path.display(), err));
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Registry::default()),
+            Err(err) => return Err(failed(err.to_string())),
+        };
+        toml::from_str(&contents).map_err(|err| failed(err.to_string()))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), CliError> {
+        let failed = |err: String| CliError::Registry(format!("{}: {}", path.display(), err));
+        let contents = toml::to_string(self).map_err(|err| failed(err.to_string()))?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|err| failed(err.to_string()))?;
+        }
+        std::fs::write(path, contents).map_err(|err| failed(err.to_string()))
+    }
+
+    // Commissioning a network again replaces what we knew about it, except
+    // for its name.
+    pub fn add(&mut self, id: &DongleId, commissioned: String) {
+        let name = self.forget(id.network).and_then(|device| device.name);
+        self.devices.push(RegisteredDevice {
+            network: id.network,
+            device: id.device,
+            name,
+            commissioned: Some(commissioned),
+        });
+        self.devices.sort_by_key(|device| device.network);
+    }
+
+    pub fn forget(&mut self, network: u16) -> Option<RegisteredDevice> {
+        let index = self.devices.iter().position(|device| device.network == network)?;
+        Some(self.devices.remove(index))
+    }
+}
+
+#[cfg(test)]
+mod test_registry {
+    use super::*;
+
+    fn id(network: u16, device: u64) -> DongleId {
+        DongleId { network, device }
+    }
+
+    #[test]
+    fn test_add_and_forget() {
+        let mut registry = Registry::default();
+        registry.add(&id(0x3b1c, 2), "2026-10-16T20:15:30Z".to_string());
+        registry.add(&id(0x215a, 1), "2026-10-16T20:15:31Z".to_string());
+        assert_eq!(registry.devices.iter().map(|device| device.network).collect::<Vec<u16>>(), vec![0x215a, 0x3b1c]);
+
+        // Commissioning again keeps the name but not the rest.
+        registry.devices[0].name = Some("Desk".to_string());
+        registry.add(&id(0x215a, 3), "2026-10-17T08:00:00Z".to_string());
+        assert_eq!(registry.devices.len(), 2);
+        assert_eq!(registry.devices[0].device, 3);
+        assert_eq!(registry.devices[0].name.as_deref(), Some("Desk"));
+        assert_eq!(registry.devices[0].commissioned.as_deref(), Some("2026-10-17T08:00:00Z"));
+
+        assert_eq!(registry.forget(0x215a).unwrap().device, 3);
+        assert!(registry.forget(0x215a).is_none());
+        assert_eq!(registry.devices.len(), 1);
+    }
+
+    #[test]
+    fn test_read_and_write() {
+        let path = std::env::temp_dir()
+            .join(format!("hacklet-registry-{}", std::process::id()))
+            .join("devices.toml");
+        assert!(Registry::read(&path).unwrap().devices.is_empty());
+
+        let mut registry = Registry::default();
+        registry.add(&id(0x215a, 0xfb2f000000584f82), "2026-10-16T20:15:30Z".to_string());
+        registry.write(&path).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        assert!(contents.contains("network = \"0x215a\""));
+        assert!(contents.contains("device = \"0xfb2f000000584f82\""));
+
+        let registry: Registry = toml::from_str(&contents).unwrap();
+        assert_eq!(registry.devices[0].network, 0x215a);
+        assert_eq!(registry.devices[0].device, 0xfb2f000000584f82);
+        assert!(registry.devices[0].name.is_none());
+    }
+
+    #[test]
+    fn test_invalid_ids() {
+        assert!(toml::from_str::<Registry>("[[devices]]\nnetwork = \"215a\"\ndevice = \"1\"").is_ok());
+        assert!(toml::from_str::<Registry>("[[devices]]\nnetwork = \"0x12345\"\ndevice = \"0x1\"").is_err());
+        assert!(toml::from_str::<Registry>("[[devices]]\nnetwork = \"0x21zz\"\ndevice = \"0x1\"").is_err());
+        assert!(toml::from_str::<Registry>("[[devices]]\nnetwork = 8538\ndevice = \"0x1\"").is_err());
+        assert!(toml::from_str::<Registry>("[[devices]]\nnetwork = \"0x215a\"\ndevice = \"0x1\"\ncolour = \"red\"").is_err());
+    }
+}
@@ -0,0 +1,75 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use hacklet::dongle::DongleError;
+use hacklet::schedule::WeeklySchedule;
+
+// A weekly program file maps days or ranges of days to lists of on
+// intervals, in the same notation as the command line:
+//
+//   "Mon-Fri" = ["07:00-09:00", "18:00-23:00"]
+//   "Sat-Sun" = ["09:00-23:30"]
+//   "Wed" = []
+//
+// An empty list leaves those days off. The same mapping is accepted as YAML
+// for files ending in .yaml or .yml.
+pub fn read_schedule_file(path: &Path) -> Result<WeeklySchedule, DongleError> {
+    let contents = std::fs::read_to_string(path)?;
+    parse_schedule(&contents, path)
+}
+
+fn parse_schedule(contents: &str, path: &Path) -> Result<WeeklySchedule, DongleError> {
+    let invalid = |err: String| DongleError::InvalidSchedule(format!("{}: {}", path.display(), err));
+
+    let program: BTreeMap<String, Vec<String>> = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("yaml") | Some("yml") => serde_yaml::from_str(contents).map_err(|err| invalid(err.to_string()))?,
+        _ => toml::from_str(contents).map_err(|err| invalid(err.to_string()))?,
+    };
+
+    let spec: Vec<String> = program.iter()
+        .map(|(days, intervals)| if intervals.is_empty() {
+            format!("{} off", days)
+        } else {
+            format!("{} {}", days, intervals.join(","))
+        })
+        .collect();
+    spec.join(";").parse()
+}
+
+#[cfg(test)]
+mod test_schedule_file {
+    use super::*;
+    use hacklet::schedule::Weekday;
+
+    #[test]
+    fn test_toml() {
+        let contents = "\"Mon-Fri\" = [\"07:00-09:00\", \"18:00-23:00\"]\n\"Sat-Sun\" = [\"09:00-23:30\"]\n";
+        let schedule = parse_schedule(contents, Path::new("week.toml")).unwrap();
+        assert_eq!(schedule, "Mon-Fri 07:00-09:00,18:00-23:00; Sat-Sun 09:00-23:30".parse().unwrap());
+    }
+
+    #[test]
+    fn test_yaml() {
+        let contents = "Mon-Fri:\n  - 07:00-09:00\n  - 18:00-23:00\nSat-Sun: [\"09:00-23:30\"]\n";
+        let schedule = parse_schedule(contents, Path::new("week.yaml")).unwrap();
+        assert_eq!(schedule, "Mon-Fri 07:00-09:00,18:00-23:00; Sat-Sun 09:00-23:30".parse().unwrap());
+    }
+
+    #[test]
+    fn test_empty_list_is_off() {
+        let schedule = parse_schedule("\"Mon\" = []\n\"Tue\" = [\"on\"]\n", Path::new("week.toml")).unwrap();
+        assert!(!schedule.is_on(Weekday::Monday, 12 * 60));
+        assert!(schedule.is_on(Weekday::Tuesday, 12 * 60));
+
+        let schedule = parse_schedule("Mon: []\n", Path::new("week.yml")).unwrap();
+        assert_eq!(schedule, WeeklySchedule::always_off());
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        let err = parse_schedule("\"Mon\" = \"07:00-09:00\"\n", Path::new("week.toml")).unwrap_err();
+        assert!(err.to_string().contains("week.toml"));
+        assert!(parse_schedule("\"Someday\" = []\n", Path::new("week.toml")).is_err());
+        assert!(parse_schedule("Mon: [\"07:00\"]\n", Path::new("week.yaml")).is_err());
+    }
+}
@@ -0,0 +1,176 @@
+use clap::Parser;
+use log::{debug, error, info, trace, warn};
+use rand::Rng;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+
+mod scenario;
+use hacklet::dongle::DongleError;
+use hacklet::emulator::Emulator;
+use hacklet::messages::{Frame, FrameReader};
+use scenario::Scenario;
+
+#[derive(Parser)]
+#[command(about = "Serve the Modlet dongle protocol on a pseudo-terminal")]
+struct Command {
+    /// Scenario file describing the dongle, its networks and link faults
+    scenario: PathBuf,
+
+    /// Also make the pseudo-terminal available under this path
+    #[arg(short, long)]
+    link: Option<PathBuf>,
+
+    /// Enable debug messages (add this twice for trace level)
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    debug: u8,
+}
+
+// What happens to replies on their way back to the host.
+struct Faults {
+    latency: Duration,
+    drop_rate: f64,
+    corrupt_rate: f64,
+    rng: StdRng,
+}
+
+impl Faults {
+    fn new(scenario: &Scenario) -> Faults {
+        Faults {
+            latency: Duration::from_millis(scenario.latency),
+            drop_rate: scenario.drop_rate,
+            corrupt_rate: scenario.corrupt_rate,
+            rng: match scenario.seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            },
+        }
+    }
+
+    fn apply(&mut self, replies: Vec<Frame>) -> Result<Vec<u8>, DongleError> {
+        let mut bytes = Vec::new();
+        for reply in replies {
+            let mut buf = reply.encode()?;
+            if self.rng.gen_bool(self.drop_rate) {
+                info!("Dropping {:x?}", reply);
+                continue;
+            }
+            if self.rng.gen_bool(self.corrupt_rate) {
+                info!("Corrupting checksum of {:x?}", reply);
+                if let Some(checksum) = buf.last_mut() {
+                    *checksum ^= 0xff;
+                }
+            }
+            bytes.extend(buf);
+        }
+        Ok(bytes)
+    }
+}
+
+#[cfg(unix)]
+fn serve(run: &Command, mut emulator: Emulator, mut faults: Faults) -> Result<(), DongleError> {
+    use nix::pty::openpty;
+    use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg};
+    use std::fs::File;
+    use std::io::{Read, Write};
+
+    // hacklet only converts nix errors with its tty feature, which the
+    // simulator doesn't need, so go through io::Error.
+    let pty = openpty(None, None).map_err(std::io::Error::from)?;
+    let mut termios = tcgetattr(&pty.slave).map_err(std::io::Error::from)?;
+    cfmakeraw(&mut termios);
+    tcsetattr(&pty.slave, SetArg::TCSANOW, &termios).map_err(std::io::Error::from)?;
+    let path = nix::unistd::ttyname(&pty.slave).map_err(std::io::Error::from)?;
+
+    if let Some(link) = &run.link {
+        if link.is_symlink() {
+            std::fs::remove_file(link)?;
+        }
+        std::os::unix::fs::symlink(&path, link)?;
+        info!("Linked {} to {}", link.display(), path.display());
+    }
+    println!("{}", path.display());
+
+    // Keep our own handle on the slave side open, otherwise reads from the
+    // master fail whenever no client has the terminal open.
+    let _slave = pty.slave;
+    let mut master = File::from(pty.master);
+    let mut reader = FrameReader::new();
+    let mut buf = [0u8; 256];
+    loop {
+        let count = master.read(&mut buf)?;
+        trace!("RX: {:x?}", &buf[..count]);
+        reader.push(&buf[..count]);
+
+        while let Some(frame) = reader.next_frame() {
+            let request = match Frame::decode(&frame) {
+                Ok(request) => request,
+                Err(err) => {
+                    warn!("Ignoring bad request {:x?}: {}", frame, err);
+                    continue;
+                },
+            };
+            debug!("Request {:x?}", request);
+            let replies = faults.apply(emulator.handle(request))?;
+            if replies.is_empty() {
+                continue;
+            }
+            std::thread::sleep(faults.latency);
+            trace!("TX: {:x?}", replies);
+            master.write_all(&replies)?;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn serve(_run: &Command, _emulator: Emulator, _faults: Faults) -> Result<(), DongleError> {
+    Err(DongleError::Unsupported("pseudo-terminals are only available on unix".to_string()))
+}
+
+fn main() -> ExitCode {
+    let run = Command::parse();
+
+    match run.debug {
+        1 => simple_logger::init_with_level(log::Level::Debug).unwrap(),
+        2 => simple_logger::init_with_level(log::Level::Trace).unwrap(),
+        _ => simple_logger::init_with_level(log::Level::Info).unwrap(),
+    }
+
+    let result = Scenario::read(&run.scenario).and_then(|scenario| {
+        let emulator = scenario.emulator()?;
+        serve(&run, emulator, Faults::new(&scenario))
+    });
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            error!("{}", err);
+            ExitCode::FAILURE
+        },
+    }
+}
+
+#[cfg(test)]
+mod test_faults {
+    use super::*;
+    use hacklet::messages::{message_checksum, LockResponse};
+
+    fn faults(drop_rate: f64, corrupt_rate: f64) -> Faults {
+        let scenario: Scenario = format!("drop_rate = {:?}\ncorrupt_rate = {:?}\nseed = 7", drop_rate, corrupt_rate).parse().unwrap();
+        Faults::new(&scenario)
+    }
+
+    #[test]
+    fn test_faults() {
+        let reply = || vec![Frame::LockResponse(LockResponse{})];
+        let clean = faults(0.0, 0.0).apply(reply()).unwrap();
+        assert_eq!(clean, Frame::LockResponse(LockResponse{}).encode().unwrap());
+
+        assert!(faults(1.0, 0.0).apply(reply()).unwrap().is_empty());
+
+        let corrupt = faults(0.0, 1.0).apply(reply()).unwrap();
+        assert_eq!(corrupt.len(), clean.len());
+        assert_ne!(message_checksum(&corrupt[1..corrupt.len() - 1]), corrupt[corrupt.len() - 1]);
+    }
+}
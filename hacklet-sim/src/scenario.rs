@@ -0,0 +1,154 @@
+use serde::Deserialize;
+use std::path::Path;
+use std::time::Duration;
+
+use hacklet::dongle::DongleError;
+use hacklet::emulator::{Emulator, EmulatedModlet, EmulatedOutlet, LoadProfile};
+use hacklet::schedule::WeeklySchedule;
+
+// Everything about the simulated dongle, its Modlets and the link to the
+// host. See scenarios/example.toml for a commented example.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+    #[serde(default = "default_device_id")]
+    pub device_id: u64,
+    // Delay before every reply, in milliseconds
+    #[serde(default)]
+    pub latency: u64,
+    // Chance of each reply being dropped or having its checksum corrupted
+    #[serde(default)]
+    pub drop_rate: f64,
+    #[serde(default)]
+    pub corrupt_rate: f64,
+    // Seed for the faults above, so a run can be repeated exactly
+    pub seed: Option<u64>,
+    #[serde(default)]
+    pub networks: Vec<NetworkSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkSpec {
+    pub network_id: u16,
+    pub device_id: u64,
+    // Modlets that are not commissioned yet announce themselves the first
+    // time the network is unlocked.
+    #[serde(default = "default_commissioned")]
+    pub commissioned: bool,
+    #[serde(default)]
+    pub outlets: Vec<OutletSpec>,
+}
+
+// An outlet draws either a constant `watts`, or cycles between `low` and
+// `high` every `period` seconds. Nothing at all means nothing is plugged in.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutletSpec {
+    pub watts: Option<f64>,
+    pub low: Option<f64>,
+    pub high: Option<f64>,
+    pub period: Option<u64>,
+    // A weekly program in the same notation as `hacklet-rs schedule`
+    pub schedule: Option<String>,
+    pub stored_samples: Option<u32>,
+}
+
+fn default_device_id() -> u64 {
+    0x0b2f000000584f80
+}
+
+fn default_commissioned() -> bool {
+    true
+}
+
+impl OutletSpec {
+    fn to_outlet(&self) -> Result<EmulatedOutlet, DongleError> {
+        let load = match (self.watts, self.low, self.high) {
+            (Some(watts), None, None) => LoadProfile::Constant(watts),
+            (None, Some(low), Some(high)) => LoadProfile::Cycle {
+                low,
+                high,
+                period: Duration::from_secs(self.period.unwrap_or(600)),
+            },
+            (None, None, None) => LoadProfile::Off,
+            _ => return Err(DongleError::InvalidInput("an outlet needs either watts, or low and high".to_string())),
+        };
+
+        let mut outlet = EmulatedOutlet::new(load);
+        if let Some(program) = &self.schedule {
+            outlet.schedule = program.parse::<WeeklySchedule>()?;
+        }
+        if let Some(stored_samples) = self.stored_samples {
+            outlet.stored_samples = stored_samples;
+        }
+        Ok(outlet)
+    }
+}
+
+impl Scenario {
+    pub fn read(path: &Path) -> Result<Scenario, DongleError> {
+        let contents = std::fs::read_to_string(path)?;
+        contents.parse()
+    }
+
+    pub fn emulator(&self) -> Result<Emulator, DongleError> {
+        let mut emulator = Emulator::new(self.device_id);
+        for network in &self.networks {
+            if network.outlets.len() > 2 {
+                return Err(DongleError::InvalidInput(format!("network 0x{:04x} has more than two outlets", network.network_id)));
+            }
+            let mut modlet = EmulatedModlet::new(network.network_id, network.device_id);
+            for (index, outlet) in network.outlets.iter().enumerate() {
+                modlet.outlets[index] = outlet.to_outlet()?;
+            }
+            if network.commissioned {
+                emulator.add_modlet(modlet);
+            } else {
+                emulator.add_pending_modlet(modlet);
+            }
+        }
+        Ok(emulator)
+    }
+}
+
+impl std::str::FromStr for Scenario {
+    type Err = DongleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let scenario: Scenario = toml::from_str(s)
+            .map_err(|err| DongleError::InvalidInput(format!("scenario: {}", err)))?;
+        for rate in [scenario.drop_rate, scenario.corrupt_rate] {
+            if !(0.0..=1.0).contains(&rate) {
+                return Err(DongleError::InvalidInput(format!("scenario: rate {} is not between 0 and 1", rate)));
+            }
+        }
+        Ok(scenario)
+    }
+}
+
+#[cfg(test)]
+mod test_scenario {
+    use super::*;
+
+    #[test]
+    fn test_example_scenario() {
+        let scenario: Scenario = include_str!("../scenarios/example.toml").parse().unwrap();
+        let emulator = scenario.emulator().unwrap();
+        assert_eq!(emulator.device_id, 0x0b2f000000584f80);
+        assert_eq!(emulator.pending.len(), 1);
+
+        let modlet = emulator.modlet(0x215a).unwrap();
+        assert_eq!(modlet.outlets[0].load, LoadProfile::Constant(60.0));
+        assert!(matches!(modlet.outlets[1].load, LoadProfile::Cycle { .. }));
+    }
+
+    #[test]
+    fn test_invalid_scenarios() {
+        assert!("drop_rate = 1.5".parse::<Scenario>().is_err());
+        assert!("lantecy = 5".parse::<Scenario>().is_err());
+
+        let scenario: Scenario = "[[networks]]\nnetwork_id = 1\ndevice_id = 2\noutlets = [{ watts = 1.0, low = 2.0 }]".parse().unwrap();
+        assert!(scenario.emulator().is_err());
+    }
+}
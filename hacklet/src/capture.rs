@@ -0,0 +1,323 @@
+use log::trace;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::LineWriter;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::error::DongleError;
+use crate::transport::Transport;
+
+// A capture is a text file with one chunk of serial traffic per line: the
+// seconds since the capture started, TX for bytes sent to the dongle or RX
+// for bytes received from it, and the bytes in hex.
+//
+//   # hacklet capture
+//   0.000000 TX 02 40 04 00 44
+//   0.001873 RX 02 40 84 16 01 00 00 87 03 00 30 00 33 83 69 9a 0b 2f 00 00 00 58 4f 80 0a 1c 81
+//
+// Lines starting with # are comments. A TX byte may be written as ?? to
+// match anything on replay, for fields such as the time that change from
+// run to run.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Tx,
+    Rx,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CaptureEntry {
+    pub elapsed: Duration,
+    pub direction: Direction,
+    // None is a wildcard byte
+    pub data: Vec<Option<u8>>,
+}
+
+impl CaptureEntry {
+    pub fn bytes(&self) -> Vec<u8> {
+        self.data.iter().map(|byte| byte.unwrap_or(0)).collect()
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Capture {
+    pub entries: Vec<CaptureEntry>,
+}
+
+impl Capture {
+    pub fn read(path: &Path) -> Result<Capture, DongleError> {
+        std::fs::read_to_string(path)?.parse()
+    }
+}
+
+impl fmt::Display for CaptureEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let direction = match self.direction {
+            Direction::Tx => "TX",
+            Direction::Rx => "RX",
+        };
+        write!(f, "{:.6} {}", self.elapsed.as_secs_f64(), direction)?;
+        for byte in &self.data {
+            match byte {
+                Some(byte) => write!(f, " {:02x}", byte)?,
+                None => write!(f, " ??")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Capture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "# hacklet capture")?;
+        for entry in &self.entries {
+            writeln!(f, "{}", entry)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for CaptureEntry {
+    type Err = DongleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| DongleError::InvalidInput(format!("capture line {:?}: {}", s, reason));
+        let mut fields = s.split_whitespace();
+        let elapsed = fields.next()
+            .and_then(|seconds| seconds.parse::<f64>().ok())
+            .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+            .ok_or_else(|| invalid("bad timestamp"))?;
+        let direction = match fields.next() {
+            Some("TX") => Direction::Tx,
+            Some("RX") => Direction::Rx,
+            _ => return Err(invalid("direction must be TX or RX")),
+        };
+        let data = fields
+            .map(|byte| match byte {
+                "??" if direction == Direction::Tx => Ok(None),
+                _ => u8::from_str_radix(byte, 16).map(Some).map_err(|_| invalid("bad hex byte")),
+            })
+            .collect::<Result<Vec<Option<u8>>, DongleError>>()?;
+        Ok(CaptureEntry { elapsed, direction, data })
+    }
+}
+
+impl FromStr for Capture {
+    type Err = DongleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let entries = s.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::parse)
+            .collect::<Result<Vec<CaptureEntry>, DongleError>>()?;
+        Ok(Capture { entries })
+    }
+}
+
+// Passes everything through to another transport, writing each chunk to a
+// capture as it goes.
+pub struct RecordingTransport {
+    inner: Box<dyn Transport>,
+    output: Box<dyn Write>,
+    start: Instant,
+}
+
+impl RecordingTransport {
+    pub fn new(inner: Box<dyn Transport>, output: Box<dyn Write>) -> Result<RecordingTransport, DongleError> {
+        let mut recorder = RecordingTransport {
+            inner,
+            output,
+            start: Instant::now(),
+        };
+        writeln!(recorder.output, "# hacklet capture")?;
+        Ok(recorder)
+    }
+
+    pub fn create(inner: Box<dyn Transport>, path: &Path) -> Result<RecordingTransport, DongleError> {
+        let file = LineWriter::new(File::create(path)?);
+        RecordingTransport::new(inner, Box::new(file))
+    }
+
+    fn record(&mut self, direction: Direction, data: &[u8]) -> Result<(), DongleError> {
+        let entry = CaptureEntry {
+            elapsed: self.start.elapsed(),
+            direction,
+            data: data.iter().copied().map(Some).collect(),
+        };
+        writeln!(self.output, "{}", entry)?;
+        Ok(())
+    }
+}
+
+impl Transport for RecordingTransport {
+    fn transmit(&mut self, data: &[u8]) -> Result<usize, DongleError> {
+        let count = self.inner.transmit(data)?;
+        self.record(Direction::Tx, &data[..count])?;
+        Ok(count)
+    }
+
+    fn receive(&mut self, max_bytes: usize, deadline: Instant) -> Result<Vec<u8>, DongleError> {
+        let data = self.inner.receive(max_bytes, deadline)?;
+        self.record(Direction::Rx, &data)?;
+        Ok(data)
+    }
+
+    fn close(&mut self) {
+        let _ = self.output.flush();
+        self.inner.close()
+    }
+}
+
+// Plays a capture back to a Dongle without any hardware, panicking as soon as
+// the Dongle transmits something other than what was captured. Timestamps are
+// ignored: received bytes are available immediately, and when the capture
+// expects the Dongle to transmit next, receive times out straight away.
+pub struct ReplayTransport {
+    entries: VecDeque<CaptureEntry>,
+    rx: VecDeque<u8>,
+    tx: VecDeque<Option<u8>>,
+    transmitted: usize,
+}
+
+impl ReplayTransport {
+    pub fn new(capture: Capture) -> ReplayTransport {
+        ReplayTransport {
+            entries: capture.entries.into(),
+            rx: VecDeque::new(),
+            tx: VecDeque::new(),
+            transmitted: 0,
+        }
+    }
+
+    // Moves the next entry into the rx or tx queue. Anything received before
+    // the next transmission is already waiting in the input buffer, just like
+    // on a real serial port.
+    fn advance(&mut self) -> Option<Direction> {
+        let entry = self.entries.pop_front()?;
+        match entry.direction {
+            Direction::Tx => self.tx.extend(entry.data),
+            Direction::Rx => self.rx.extend(entry.bytes()),
+        }
+        Some(entry.direction)
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn transmit(&mut self, data: &[u8]) -> Result<usize, DongleError> {
+        trace!("TX: {:x?}", data);
+        while self.tx.len() < data.len() && self.advance().is_some() {}
+
+        let expected: Vec<Option<u8>> = self.tx.drain(..data.len().min(self.tx.len())).collect();
+        let matches = expected.len() == data.len() &&
+            expected.iter().zip(data).all(|(expected, actual)| expected.is_none_or(|expected| expected == *actual));
+        assert!(matches, "transmitted {:02x?} at byte {} of the capture, expected {:02x?}",
+                data, self.transmitted, expected);
+        self.transmitted += data.len();
+        Ok(data.len())
+    }
+
+    fn receive(&mut self, max_bytes: usize, _deadline: Instant) -> Result<Vec<u8>, DongleError> {
+        while self.rx.is_empty() && self.tx.is_empty() {
+            match self.entries.front() {
+                Some(entry) if entry.direction == Direction::Rx => { self.advance(); },
+                _ => break,
+            }
+        }
+        if self.rx.is_empty() {
+            return Err(DongleError::Timeout);
+        }
+        let count = max_bytes.min(self.rx.len());
+        let bytes: Vec<u8> = self.rx.drain(..count).collect();
+        trace!("RX: {:x?}", bytes);
+        Ok(bytes)
+    }
+
+    fn close(&mut self) {
+        let untransmitted = self.tx.len() + self.entries.iter()
+            .filter(|entry| entry.direction == Direction::Tx)
+            .map(|entry| entry.data.len())
+            .sum::<usize>();
+        assert!(untransmitted == 0 || std::thread::panicking(),
+                "closed with {} bytes of the capture not transmitted", untransmitted);
+    }
+}
+
+#[cfg(test)]
+mod test_capture {
+    use super::*;
+    use crate::dongle::{CommissionStatus, Dongle, SwitchState};
+
+    fn replay(capture: &str) -> Dongle {
+        let capture: Capture = capture.parse().unwrap();
+        Dongle::with_transport(Box::new(ReplayTransport::new(capture))).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let text = include_str!("../captures/commission.txt");
+        let capture: Capture = text.parse().unwrap();
+        assert_eq!(capture.entries.len(), 19);
+        assert_eq!(capture.entries[0], CaptureEntry {
+            elapsed: Duration::from_micros(58),
+            direction: Direction::Tx,
+            data: vec![Some(0x02), Some(0x40), Some(0x04), Some(0x00), Some(0x44)],
+        });
+        assert_eq!(capture.to_string().parse::<Capture>().unwrap(), capture);
+
+        assert!("0.1 XX 02".parse::<Capture>().is_err());
+        assert!("0.1 RX ??".parse::<Capture>().is_err());
+    }
+
+    #[test]
+    fn test_replay_commission() {
+        let mut dongle = replay(include_str!("../captures/commission.txt"));
+        let status = dongle.commission().unwrap();
+        assert!(matches!(status, CommissionStatus::Commissioned(id) if id.network == 0x3b1c && id.device == 0x0b2f000000584f82));
+        dongle.close();
+    }
+
+    #[test]
+    fn test_replay_switch() {
+        let mut dongle = replay(include_str!("../captures/switch.txt"));
+        dongle.switch(0x215a, 1, SwitchState::AlwaysOff).unwrap();
+        dongle.close();
+    }
+
+    #[test]
+    fn test_replay_request_samples() {
+        let mut dongle = replay(include_str!("../captures/request_samples.txt"));
+        assert_eq!(dongle.request_samples(0x215a, 0).unwrap(), vec![780; 6]);
+        dongle.close();
+    }
+
+    #[test]
+    #[should_panic(expected = "expected")]
+    fn test_replay_mismatch() {
+        let mut dongle = replay(include_str!("../captures/switch.txt"));
+        let _ = dongle.switch(0x215a, 0, SwitchState::AlwaysOff);
+    }
+
+    #[test]
+    fn test_recording() {
+        let capture: Capture = include_str!("../captures/switch.txt").parse().unwrap();
+        let path = std::env::temp_dir().join(format!("hacklet-capture-{}.txt", std::process::id()));
+        let recorder = RecordingTransport::create(Box::new(ReplayTransport::new(capture.clone())), &path).unwrap();
+        let mut dongle = Dongle::with_transport(Box::new(recorder)).unwrap();
+        dongle.switch(0x215a, 1, SwitchState::AlwaysOff).unwrap();
+        dongle.close();
+
+        let recorded = Capture::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let bytes = |capture: &Capture, direction| -> Vec<u8> {
+            capture.entries.iter().filter(|entry| entry.direction == direction).flat_map(|entry| entry.bytes()).collect()
+        };
+        assert_eq!(bytes(&recorded, Direction::Tx), bytes(&capture, Direction::Tx));
+        assert_eq!(bytes(&recorded, Direction::Rx), bytes(&capture, Direction::Rx));
+    }
+}
@@ -1,51 +1,45 @@
-use log::debug;
+use binrw::BinRead;
+use binrw::meta::ReadEndian;
+use log::{debug, warn};
+use std::collections::VecDeque;
+#[cfg(all(unix, feature = "tty"))]
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
 use std::time::Duration;
 use std::time::Instant;
 use std::time::SystemTime;
 
+pub use crate::error::DongleError;
 use crate::messages::*;
-use crate::serial_connection;
-
-// TODO: more helpful errors
-#[derive(Debug)]
-pub enum DongleError {
-    MessageFailure,
-    SerialConnectionError,
-}
-
-impl From<binrw::Error> for DongleError {
-    fn from(_: binrw::Error) -> Self {
-        DongleError::MessageFailure
-    }
-}
-
-// TODO: more helpful d2xx error conversions
-impl From<libftd2xx::FtStatus> for DongleError {
-    fn from(status: libftd2xx::FtStatus) -> Self {
-        match status {
-            libftd2xx::FtStatus::INVALID_HANDLE => Self::SerialConnectionError,
-            libftd2xx::FtStatus::DEVICE_NOT_FOUND => Self::SerialConnectionError,
-            libftd2xx::FtStatus::DEVICE_NOT_OPENED => Self::SerialConnectionError,
-            libftd2xx::FtStatus::IO_ERROR => Self::SerialConnectionError,
-            libftd2xx::FtStatus::INSUFFICIENT_RESOURCES => Self::SerialConnectionError,
-            libftd2xx::FtStatus::INVALID_PARAMETER => Self::SerialConnectionError,
-            libftd2xx::FtStatus::INVALID_BAUD_RATE => Self::SerialConnectionError,
-            libftd2xx::FtStatus::DEVICE_NOT_OPENED_FOR_ERASE => Self::SerialConnectionError,
-            libftd2xx::FtStatus::DEVICE_NOT_OPENED_FOR_WRITE => Self::SerialConnectionError,
-            libftd2xx::FtStatus::FAILED_TO_WRITE_DEVICE => Self::SerialConnectionError,
-            libftd2xx::FtStatus::EEPROM_READ_FAILED => Self::SerialConnectionError,
-            libftd2xx::FtStatus::EEPROM_WRITE_FAILED => Self::SerialConnectionError,
-            libftd2xx::FtStatus::EEPROM_ERASE_FAILED => Self::SerialConnectionError,
-            libftd2xx::FtStatus::EEPROM_NOT_PRESENT => Self::SerialConnectionError,
-            libftd2xx::FtStatus::EEPROM_NOT_PROGRAMMED => Self::SerialConnectionError,
-            libftd2xx::FtStatus::INVALID_ARGS => Self::SerialConnectionError,
-            libftd2xx::FtStatus::NOT_SUPPORTED => Self::SerialConnectionError,
-            libftd2xx::FtStatus::OTHER_ERROR => Self::SerialConnectionError,
-            libftd2xx::FtStatus::DEVICE_LIST_NOT_READY => Self::SerialConnectionError,
-        }
-    }
-}
-
+use crate::power::History;
+use crate::power::SampleBatch;
+use crate::schedule::WeeklySchedule;
+#[cfg(feature = "d2xx")]
+use crate::serial_connection::SerialConnection;
+use crate::transport::SerialSettings;
+use crate::transport::Transport;
+#[cfg(all(unix, feature = "tty"))]
+use crate::tty_connection::TtyConnection;
+
+// How long to wait for the dongle to answer a single request, unless the
+// DongleBuilder says otherwise.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long commission keeps the network open for a new device.
+const COMMISSION_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How often long waits stop to check whether they were interrupted.
+const INTERRUPT_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+// Unsolicited messages beyond this are dropped, oldest first, if nobody is
+// reading events.
+const MAX_QUEUED_EVENTS: usize = 64;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct DongleId {
     pub device: u64,
     pub network: u16,
@@ -56,146 +50,486 @@ pub enum CommissionStatus {
     Unknown,
 }
 
+// What commission_many should do with a device it just heard from. There is
+// no known way to turn a Modlet away once it has joined the unlocked
+// network, so Reject only means it is not time synced or returned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommissionDecision {
+    Accept,
+    Reject,
+    // Accept this device, then stop listening
+    AcceptAndStop,
+    // Stop listening without accepting this device
+    Stop,
+}
+
 pub enum SwitchState {
     AlwaysOn,
     AlwaysOff,
 }
 
+// Which dongle to open when more than one is plugged in.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DongleSelector {
+    // The FTDI serial number
+    Serial(String),
+    // The position among connected Modlet dongles, starting from 0
+    Index(usize),
+    // A tty created by the kernel ftdi_sio driver, e.g. /dev/ttyUSB0
+    Path(PathBuf),
+}
+
+impl DongleSelector {
+    #[cfg_attr(not(any(feature = "d2xx", all(unix, feature = "tty"))), allow(unused_variables))]
+    pub fn open_transport(&self, settings: &SerialSettings) -> Result<Box<dyn Transport>, DongleError> {
+        match self {
+            #[cfg(feature = "d2xx")]
+            DongleSelector::Serial(serial_number) => Ok(Box::new(SerialConnection::with_serial_number(serial_number, settings)?)),
+            #[cfg(feature = "d2xx")]
+            DongleSelector::Index(index) => Ok(Box::new(SerialConnection::with_index(*index, settings)?)),
+            #[cfg(not(feature = "d2xx"))]
+            DongleSelector::Serial(_) | DongleSelector::Index(_) => {
+                Err(DongleError::Unsupported("built without D2XX support, select the dongle by path".to_string()))
+            },
+            #[cfg(all(unix, feature = "tty"))]
+            DongleSelector::Path(path) => Ok(Box::new(TtyConnection::new(path, settings)?)),
+            #[cfg(not(all(unix, feature = "tty")))]
+            DongleSelector::Path(_) => {
+                Err(DongleError::Unsupported("built without tty support, select the dongle by serial number or index".to_string()))
+            },
+        }
+    }
+}
+
+// A plain number is an index and anything with a slash in it is a path.
+// Everything else is taken as a serial number, unless it is prefixed with
+// "serial:", "index:" or "path:" to say which it is.
+impl FromStr for DongleSelector {
+    type Err = DongleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid_index = |_| DongleError::InvalidInput(format!("{:?} is not a dongle index", s));
+        if let Some(serial_number) = s.strip_prefix("serial:") {
+            return Ok(DongleSelector::Serial(serial_number.to_string()));
+        }
+        if let Some(index) = s.strip_prefix("index:") {
+            return index.parse().map(DongleSelector::Index).map_err(invalid_index);
+        }
+        if let Some(path) = s.strip_prefix("path:") {
+            return Ok(DongleSelector::Path(PathBuf::from(path)));
+        }
+
+        if !s.is_empty() && s.chars().all(|c| c.is_ascii_digit()) {
+            s.parse().map(DongleSelector::Index).map_err(invalid_index)
+        } else if s.contains('/') {
+            Ok(DongleSelector::Path(PathBuf::from(s)))
+        } else if !s.is_empty() {
+            Ok(DongleSelector::Serial(s.to_string()))
+        } else {
+            Err(DongleError::InvalidInput("no dongle given".to_string()))
+        }
+    }
+}
+
+// What the dongle reports about itself when it boots. Only the device ID is
+// understood so far. Nobody knows which of the other bytes hold firmware or
+// hardware versions, so they are kept undecoded, exactly as received, which
+// is still enough to tell dongles apart and to compare them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DongleInfo {
+    pub device_id: u64,
+    pub raw_boot_data: [u8; 12],
+    pub raw_boot_data2: u16,
+}
+
+impl From<&BootResponse> for DongleInfo {
+    fn from(response: &BootResponse) -> Self {
+        DongleInfo {
+            device_id: response.device_id,
+            raw_boot_data: response.data,
+            raw_boot_data2: response.data2,
+        }
+    }
+}
+
+// Opens a Dongle with anything other than the default settings.
+pub struct DongleBuilder {
+    selector: Option<DongleSelector>,
+    settings: SerialSettings,
+    response_timeout: Duration,
+    utc_offset: i32,
+    boot: bool,
+}
+
+impl Default for DongleBuilder {
+    fn default() -> Self {
+        DongleBuilder {
+            selector: None,
+            settings: SerialSettings::default(),
+            response_timeout: RESPONSE_TIMEOUT,
+            utc_offset: 0,
+            boot: true,
+        }
+    }
+}
+
+impl DongleBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Without a selector the first D2XX dongle is opened.
+    pub fn selector(mut self, selector: DongleSelector) -> Self {
+        self.selector = Some(selector);
+        self
+    }
+
+    pub fn vid_pid(mut self, vendor_id: u16, product_id: u16) -> Self {
+        self.settings.vendor_id = vendor_id;
+        self.settings.product_id = product_id;
+        self
+    }
+
+    pub fn baud_rate(mut self, baud_rate: u32) -> Self {
+        self.settings.baud_rate = baud_rate;
+        self
+    }
+
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.settings.write_timeout = timeout;
+        self
+    }
+
+    pub fn purge(mut self, purge: bool) -> Self {
+        self.settings.purge = purge;
+        self
+    }
+
+    // How long to wait for the answer to each request.
+    pub fn response_timeout(mut self, timeout: Duration) -> Self {
+        self.response_timeout = timeout;
+        self
+    }
+
+    // Skipping the boot handshake is useful when something else already
+    // booted the dongle, but leaves Dongle::info empty.
+    pub fn boot(mut self, boot: bool) -> Self {
+        self.boot = boot;
+        self
+    }
+
+    // Seconds east of UTC to set Modlet clocks to, so that their schedules
+    // run on local time. Sample times are shifted back to UTC when read.
+    pub fn utc_offset(mut self, seconds: i32) -> Self {
+        self.utc_offset = seconds;
+        self
+    }
+
+    pub fn settings(&self) -> &SerialSettings {
+        &self.settings
+    }
+
+    pub fn open_transport(&self) -> Result<Box<dyn Transport>, DongleError> {
+        match &self.selector {
+            Some(selector) => selector.open_transport(&self.settings),
+            #[cfg(feature = "d2xx")]
+            None => Ok(Box::new(SerialConnection::new(&self.settings)?)),
+            #[cfg(not(feature = "d2xx"))]
+            None => Err(DongleError::Unsupported("built without D2XX support, select the dongle by path".to_string())),
+        }
+    }
+
+    pub fn open(self) -> Result<Dongle, DongleError> {
+        let transport = self.open_transport()?;
+        self.with_transport(transport)
+    }
+
+    pub fn with_transport(self, transport: Box<dyn Transport>) -> Result<Dongle, DongleError> {
+        let mut dongle = Dongle {
+            transport,
+            reader: FrameReader::new(),
+            events: VecDeque::new(),
+            info: None,
+            current_network: None,
+            response_timeout: self.response_timeout,
+            utc_offset: self.utc_offset,
+            interrupted: Arc::new(AtomicBool::new(false)),
+        };
+        if self.boot {
+            let boot = dongle.boot()?;
+            dongle.info = Some(DongleInfo::from(&boot));
+            dongle.boot_confirm()?;
+        }
+        Ok(dongle)
+    }
+}
+
+// What a Modlet clock set with the given UTC offset reads at a given time,
+// if it can hold that time at all.
+fn modlet_clock(time: SystemTime, utc_offset: i32) -> Option<u32> {
+    let seconds = time.duration_since(SystemTime::UNIX_EPOCH).ok()?.as_secs();
+    let clock = (seconds as i64).checked_add(utc_offset as i64)?;
+    u32::try_from(clock).ok()
+}
+
 pub struct Dongle {
-    pub serial: serial_connection::SerialConnection,
+    pub transport: Box<dyn Transport>,
+    reader: FrameReader,
+    events: VecDeque<Frame>,
+    info: Option<DongleInfo>,
+    // The network the last handshake was for, if it is still known to be
+    // selected.
+    current_network: Option<u16>,
+    response_timeout: Duration,
+    utc_offset: i32,
+    interrupted: Arc<AtomicBool>,
 }
 
 impl Dongle {
+    #[cfg(feature = "d2xx")]
     pub fn open() -> Result<Dongle, DongleError> {
-        let serial = serial_connection::SerialConnection::new()?;
-        let mut dongle = Dongle { serial: serial };
-        dongle.boot()?;
-        dongle.boot_confirm()?;
-        Ok(dongle)
+        DongleBuilder::new().open()
+    }
+
+    #[cfg(all(unix, feature = "tty"))]
+    pub fn open_port(path: &Path) -> Result<Dongle, DongleError> {
+        DongleBuilder::new().selector(DongleSelector::Path(path.to_path_buf())).open()
+    }
+
+    pub fn open_with(selector: &DongleSelector) -> Result<Dongle, DongleError> {
+        DongleBuilder::new().selector(selector.clone()).open()
+    }
+
+    pub fn with_transport(transport: Box<dyn Transport>) -> Result<Dongle, DongleError> {
+        DongleBuilder::new().with_transport(transport)
+    }
+
+    // What the dongle said when it booted, unless the boot was skipped.
+    pub fn info(&self) -> Option<&DongleInfo> {
+        self.info.as_ref()
     }
 
     pub fn commission(&mut self) -> Result<CommissionStatus, DongleError> {
-        debug!("Listening for devices...");
+        self.commission_within(COMMISSION_TIMEOUT)
+    }
+
+    // Adds the first device heard before the timeout. The network is locked
+    // again whether or not one turned up.
+    pub fn commission_within(&mut self, timeout: Duration) -> Result<CommissionStatus, DongleError> {
+        let accepted = self.commission_many(timeout, |_| CommissionDecision::AcceptAndStop)?;
+        match accepted.into_iter().next() {
+            Some(id) => Ok(CommissionStatus::Commissioned(id)),
+            None => Ok(CommissionStatus::NotCommissioned),
+        }
+    }
 
+    // Keeps the network unlocked until the timeout runs out, the callback
+    // says to stop, or the interrupt flag is set, and returns every device
+    // that was accepted. Each device is only offered to the callback once,
+    // however often it broadcasts. The network is locked again however this
+    // ends. The interrupt flag is left set for the caller to clear.
+    pub fn commission_many<F>(&mut self, timeout: Duration, mut decide: F) -> Result<Vec<DongleId>, DongleError>
+    where
+        F: FnMut(&DongleId) -> CommissionDecision
+    {
+        debug!("Listening for devices...");
         self.unlock_network()?;
 
-        let timeout = Duration::from_secs(30);
         let until = Instant::now() + timeout;
-
-        loop {
-            if Instant::now() > until {
-                break;
+        let mut seen: Vec<DongleId> = Vec::new();
+        let mut accepted = Vec::new();
+        let result = loop {
+            if self.interrupted.load(Ordering::SeqCst) {
+                debug!("Commissioning interrupted");
+                break Ok(());
             }
-            debug!("Waiting for broadcast...");
-
-            let header_buf = self.serial.receive(4)?;
-            let remaining_bytes = (header_buf[3] + 1) as usize;
-            let payload_buf = self.serial.receive(remaining_bytes)?;
-            let total_len = header_buf.len() + payload_buf.len();
-            let mut buf = vec![0u8; total_len];
-            buf[..4].copy_from_slice(&header_buf);
-            buf[4..].copy_from_slice(&payload_buf);
-            if buf[1] != 0xa0 {
-                continue;
+            let now = Instant::now();
+            if now >= until {
+                break Ok(());
             }
-            let response = read_message_from_buf::<BroadcastResponse>(&buf)?;
-            debug!("Found device {:?} on network {:?}", response.device_id, response.network_id);
-
-            let current_time = SystemTime::now();
-            let since_epoch = current_time.duration_since(SystemTime::UNIX_EPOCH);
-            match since_epoch {
-                Ok(time) => {
-                    let timestamp = time.as_secs() as u32; // Warning: u64->u32 conversion loss
-                    self.update_time(response.network_id, timestamp)?;
-                },
-                Err(_) => (),
-            };
-
-            self.lock_network()?;
 
-            let dongle_id = DongleId {
+            let response = match self.receive_broadcast(until.min(now + INTERRUPT_CHECK_INTERVAL)) {
+                Ok(response) => response,
+                // A garbled broadcast only loses that device this time round,
+                // it shouldn't end commissioning for everyone else.
+                Err(DongleError::Timeout) | Err(DongleError::ChecksumMismatch { .. }) => continue,
+                Err(err) => break Err(err),
+            };
+            let id = DongleId {
                 device: response.device_id,
                 network: response.network_id,
             };
-            return Ok(CommissionStatus::Commissioned(dongle_id))
-        }
+            if seen.contains(&id) {
+                continue;
+            }
+            debug!("Found device {:?} on network {:?}", id.device, id.network);
+            seen.push(id.clone());
+
+            let decision = decide(&id);
+            if matches!(decision, CommissionDecision::Accept | CommissionDecision::AcceptAndStop) {
+                // A device that just joined the unlocked network takes its
+                // time straight away, without a handshake, as the original
+                // software commissions it.
+                let synced = self.modlet_time(SystemTime::now()).and_then(|clock| self.update_time(id.network, clock));
+                if let Err(err) = synced {
+                    break Err(err);
+                }
+                accepted.push(id);
+            }
+            if matches!(decision, CommissionDecision::AcceptAndStop | CommissionDecision::Stop) {
+                break Ok(());
+            }
+        };
+
+        let locked = self.lock_network();
+        result?;
+        locked?;
+        Ok(accepted)
+    }
 
+    // Lets another thread, such as a Ctrl-C handler, cut long waits short.
+    pub fn interrupt_flag(&self) -> Arc<AtomicBool> {
+        self.interrupted.clone()
+    }
 
-        Ok(CommissionStatus::Unknown)
+    // Sets the Modlet clock to the current time.
+    pub fn sync_time(&mut self, network_id: u16) -> Result<UpdateTimeResponse, DongleError> {
+        self.set_time(network_id, SystemTime::now())
+    }
+
+    // Sets the Modlet clock to the given time plus the UTC offset. Times the
+    // clock can't hold, before 1970 or from 2106 on, are refused rather than
+    // wrapped around.
+    pub fn set_time(&mut self, network_id: u16, time: SystemTime) -> Result<UpdateTimeResponse, DongleError> {
+        let clock = self.modlet_time(time)?;
+        self.with_network(network_id, |dongle| dongle.update_time(network_id, clock))
+    }
+
+    fn modlet_time(&self, time: SystemTime) -> Result<u32, DongleError> {
+        modlet_clock(time, self.utc_offset).ok_or(DongleError::TimeOutOfRange(time))
     }
 
     pub fn select_network(&mut self, network_id: u16) -> Result<HandshakeResponse, DongleError> {
         debug!("Selecting network {:?}", network_id);
+        self.current_network = None;
         let request = HandshakeRequest{network_id};
         let data = create_message_buf(&request)?;
-        self.serial.transmit(&data)?;
+        self.transport.transmit(&data)?;
 
-        let returned = self.serial.receive(6)?;
-        let response = read_message_from_buf::<HandshakeResponse>(&returned)?;
+        let response = self.receive_message::<HandshakeResponse>(Instant::now() + self.response_timeout)?;
+        self.current_network = Some(network_id);
         Ok(response)
     }
 
+    // Runs a request for one network, first sending the handshake unless that
+    // network is already selected. After any error the selection is unknown,
+    // so the next request handshakes again.
+    fn with_network<T, F>(&mut self, network_id: u16, request: F) -> Result<T, DongleError>
+    where
+        F: FnOnce(&mut Dongle) -> Result<T, DongleError>
+    {
+        if self.current_network != Some(network_id) {
+            self.select_network(network_id)?;
+        }
+        let result = request(self);
+        if result.is_err() {
+            self.current_network = None;
+        }
+        result
+    }
+
     pub fn request_samples(&mut self, network_id: u16, channel_id: u16) -> Result<Vec<u16>, DongleError> {
-        debug!("Requesting samples {:?}/{:?}", network_id, channel_id);
-        let request = SamplesRequest{network_id, channel_id};
-        let data = create_message_buf(&request)?;
-        self.serial.transmit(&data)?;
+        let response = self.request_samples_response(network_id, channel_id)?;
+        Ok(response.samples)
+    }
+
+    pub fn read_power(&mut self, network_id: u16, channel_id: u16) -> Result<SampleBatch, DongleError> {
+        let response = self.request_samples_response(network_id, channel_id)?;
+        Ok(SampleBatch::from(&response).to_utc(self.utc_offset))
+    }
 
-        let returned = self.serial.receive(6)?;
-        let _ = read_message_from_buf::<AckResponse>(&returned)?;
+    // Keeps asking for samples until the device reports nothing more stored,
+    // or stops making progress draining its buffer.
+    pub fn read_history(&mut self, network_id: u16, channel_id: u16) -> Result<History, DongleError> {
+        let mut batches: Vec<SampleBatch> = Vec::new();
+        loop {
+            let batch = self.read_power(network_id, channel_id)?;
+            debug!("Read {:?} samples, {:?} still stored", batch.readings.len(), batch.stored_sample_count);
+            let remaining = batch.stored_sample_count;
+            let stalled = batch.readings.is_empty() ||
+                batches.last().is_some_and(|previous| remaining >= previous.stored_sample_count);
+            batches.push(batch);
+            if remaining == 0 {
+                break;
+            }
+            if stalled {
+                warn!("Device still reports {:?} stored samples but is not returning them", remaining);
+                break;
+            }
+        }
+        Ok(History::from_batches(&batches))
+    }
 
-        let header_buf = self.serial.receive(4)?;
-        let remaining_bytes = (header_buf[3] + 1) as usize;
-        let payload_buf = self.serial.receive(remaining_bytes)?;
-        let total_len = header_buf.len() + payload_buf.len();
-        let mut buf = vec![0u8; total_len];
-        buf[..4].copy_from_slice(&header_buf);
-        buf[4..].copy_from_slice(&payload_buf);
+    fn request_samples_response(&mut self, network_id: u16, channel_id: u16) -> Result<SamplesResponse, DongleError> {
+        self.with_network(network_id, |dongle| {
+            debug!("Requesting samples {:?}/{:?}", network_id, channel_id);
+            let request = SamplesRequest{network_id, channel_id};
+            let data = create_message_buf(&request)?;
+            dongle.transport.transmit(&data)?;
 
-        let response = read_message_from_buf::<SamplesResponse>(&buf)?;
+            dongle.receive_message::<AckResponse>(Instant::now() + dongle.response_timeout)?;
 
-        Ok(response.samples)
+            let response = dongle.receive_message::<SamplesResponse>(Instant::now() + dongle.response_timeout)?;
+
+            Ok(response)
+        })
     }
 
     pub fn switch(&mut self, network_id: u16, channel_id: u8, state: SwitchState) -> Result<ScheduleResponse, DongleError> {
-        let schedule: [u8; 56] = match state {
+        let schedule = match state {
             SwitchState::AlwaysOff => {
                 debug!("Turning off channel {:?} on network {:?}", channel_id, network_id);
-                let mut bitmap = [0x7f; 56];
-                bitmap[5] = 0x25;
-                bitmap
+                WeeklySchedule::always_off()
             }
             SwitchState::AlwaysOn => {
                 debug!("Turning on channel {:?} on network {:?}", channel_id, network_id);
-                let mut bitmap = [0xff; 56];
-                bitmap[5] = 0xa5;
-                bitmap
+                WeeklySchedule::always_on()
             }
         };
+        self.set_schedule(network_id, channel_id, &schedule)
+    }
+
+    // Experimental for anything but always on or always off: the bitmap
+    // layout of other programs is a guess that no capture has confirmed yet.
+    pub fn set_schedule(&mut self, network_id: u16, channel_id: u8, schedule: &WeeklySchedule) -> Result<ScheduleResponse, DongleError> {
+        debug!("Programming schedule for channel {:?} on network {:?}", channel_id, network_id);
         let schedule_request = ScheduleRequest {
             network_id,
             channel_id,
-            schedule,
+            schedule: schedule.encode()?,
         };
 
-        let mut data = create_message_buf(&schedule_request)?;
-        let size = self.serial.transmit(&mut data)?;
-        debug!("Wrote {:?} bytes", size);
+        self.with_network(network_id, |dongle| {
+            let data = create_message_buf(&schedule_request)?;
+            let size = dongle.transport.transmit(&data)?;
+            debug!("Wrote {:?} bytes", size);
 
-        let returned = self.serial.receive(6)?;
-        let response = read_message_from_buf::<ScheduleResponse>(&returned)?;
-        Ok(response)
+            let response = dongle.receive_message::<ScheduleResponse>(Instant::now() + dongle.response_timeout)?;
+            Ok(response)
+        })
     }
 
     pub fn unlock_network(&mut self) -> Result<LockResponse, DongleError> {
         debug!("Unlocking network");
+        // New devices join while the network is unlocked, so don't rely on
+        // the dongle still having the same one selected afterwards.
+        self.current_network = None;
         let request = UnlockRequest{};
-        let mut data = create_message_buf(&request)?;
-        let size = self.serial.transmit(&mut data)?;
+        let data = create_message_buf(&request)?;
+        let size = self.transport.transmit(&data)?;
         debug!("Wrote {:?} bytes", size);
 
-        let returned = self.serial.receive(6)?;
-        let response = read_message_from_buf::<LockResponse>(&returned)?;
+        let response = self.receive_message::<LockResponse>(Instant::now() + self.response_timeout)?;
         debug!("Unlock complete");
         Ok(response)
     }
@@ -203,12 +537,11 @@ impl Dongle {
     pub fn lock_network(&mut self) -> Result<LockResponse, DongleError> {
         debug!("Locking network");
         let request = LockRequest{};
-        let mut data = create_message_buf(&request)?;
-        let size = self.serial.transmit(&mut data)?;
+        let data = create_message_buf(&request)?;
+        let size = self.transport.transmit(&data)?;
         debug!("Wrote {:?} bytes", size);
 
-        let returned = self.serial.receive(6)?;
-        let response = read_message_from_buf::<LockResponse>(&returned)?;
+        let response = self.receive_message::<LockResponse>(Instant::now() + self.response_timeout)?;
         debug!("Lock complete");
         Ok(response)
 
@@ -217,12 +550,11 @@ impl Dongle {
     fn boot(&mut self) -> Result<BootResponse, DongleError> {
         debug!("Sending boot request...");
         let request = BootRequest{};
-        let mut data = create_message_buf(&request)?;
-        let size = self.serial.transmit(&mut data)?;
+        let data = create_message_buf(&request)?;
+        let size = self.transport.transmit(&data)?;
         debug!("Wrote {:?} bytes", size);
 
-        let returned = self.serial.receive(27)?;
-        let response = read_message_from_buf::<BootResponse>(&returned)?;
+        let response = self.receive_message::<BootResponse>(Instant::now() + self.response_timeout)?;
         Ok(response)
     }
 
@@ -230,32 +562,295 @@ impl Dongle {
         debug!("Sending boot confirmation request...");
         let request = BootConfirmRequest{};
         let data = create_message_buf(&request)?;
-        let size = self.serial.transmit(&data)?;
+        let size = self.transport.transmit(&data)?;
         debug!("Wrote {:?} bytes", size);
 
-        let returned = self.serial.receive(6)?;
-        let response = read_message_from_buf::<BootConfirmResponse>(&returned)?;
+        let response = self.receive_message::<BootConfirmResponse>(Instant::now() + self.response_timeout)?;
         Ok(response)
     }
 
     fn update_time(&mut self, network_id: u16, time: u32) -> Result<UpdateTimeResponse, DongleError> {
-        debug!("Updating time...");
+        debug!("Updating time on network {:?} to {:?}", network_id, time);
         let request = UpdateTimeRequest {
             network_id,
             time,
         };
         let data = create_message_buf(&request)?;
-        self.serial.transmit(&data)?;
+        self.transport.transmit(&data)?;
 
-        let ackreturned = self.serial.receive(6)?;
-        read_message_from_buf::<UpdateTimeAckResponse>(&ackreturned)?;
+        self.receive_message::<UpdateTimeAckResponse>(Instant::now() + self.response_timeout)?;
 
-        let returned = self.serial.receive(8)?;
-        let response = read_message_from_buf::<UpdateTimeResponse>(&returned)?;
+        let response = self.receive_message::<UpdateTimeResponse>(Instant::now() + self.response_timeout)?;
         Ok(response)
     }
 
-    pub fn drop(&mut self) {
-        self.serial.close()
+    // Sends any message as it is, for exploring commands that the methods
+    // above don't cover. Whatever comes back shows up through wait_event.
+    pub fn send_frame(&mut self, frame: &Frame) -> Result<(), DongleError> {
+        debug!("Sending {:x?}", frame);
+        // This could be anything, including a handshake.
+        self.current_network = None;
+        let data = frame.encode()?;
+        self.transport.transmit(&data)?;
+        Ok(())
+    }
+
+    // Returns the next unsolicited message, either one that arrived while
+    // waiting on an earlier response or a new one read within the timeout.
+    pub fn wait_event(&mut self, timeout: Duration) -> Result<Option<Frame>, DongleError> {
+        if let Some(frame) = self.events.pop_front() {
+            return Ok(Some(frame));
+        }
+        match self.receive_frame(Instant::now() + timeout) {
+            Ok(buf) => Ok(Some(Frame::decode(&buf)?)),
+            Err(DongleError::Timeout) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn poll_event(&mut self) -> Option<Frame> {
+        self.events.pop_front()
+    }
+
+    fn queue_event(&mut self, frame: Frame) {
+        debug!("Queueing unsolicited message {:x?}", frame);
+        if self.events.len() >= MAX_QUEUED_EVENTS {
+            let dropped = self.events.pop_front();
+            debug!("Event queue full, dropping {:x?}", dropped);
+        }
+        self.events.push_back(frame);
+    }
+
+    // Reads messages until one with the command ID of T shows up, queueing
+    // everything else as an event.
+    fn receive_message<T>(&mut self, deadline: Instant) -> Result<T, DongleError>
+    where
+        T: Message + for<'a> BinRead<Args<'a> = ()> + ReadEndian + PartialEq
+    {
+        // A garbled message from before this request can't be its response.
+        self.reader.take_bad_checksum();
+        loop {
+            let buf = match self.receive_frame(deadline) {
+                Ok(buf) => buf,
+                Err(DongleError::Timeout) => return Err(match self.reader.take_bad_checksum() {
+                    Some((expected, actual)) => DongleError::ChecksumMismatch { expected, actual },
+                    None => DongleError::Timeout,
+                }),
+                Err(err) => return Err(err),
+            };
+            let command = u16::from_be_bytes([buf[1], buf[2]]);
+            if command == T::COMMAND {
+                return decode_message::<T>(&buf);
+            }
+            let frame = Frame::decode(&buf)?;
+            self.queue_event(frame);
+        }
+    }
+
+    fn receive_broadcast(&mut self, deadline: Instant) -> Result<BroadcastResponse, DongleError> {
+        let queued = self.events.iter().position(|frame| matches!(frame, Frame::BroadcastResponse(_)));
+        if let Some(Frame::BroadcastResponse(response)) = queued.and_then(|index| self.events.remove(index)) {
+            return Ok(response);
+        }
+        self.receive_message::<BroadcastResponse>(deadline)
+    }
+
+    fn receive_frame(&mut self, deadline: Instant) -> Result<Vec<u8>, DongleError> {
+        loop {
+            if let Some(frame) = self.reader.next_frame() {
+                return Ok(frame);
+            }
+            let bytes = self.transport.receive(self.reader.bytes_wanted(), deadline)?;
+            self.reader.push(&bytes);
+        }
+    }
+
+    pub fn close(&mut self) {
+        self.transport.close()
+    }
+}
+
+#[cfg(test)]
+mod test_dongle {
+    use super::*;
+
+    const BOOT_RESPONSE: [u8; 27] = [0x02, 0x40, 0x84, 0x16, 0x01, 0x00, 0x00, 0x87, 0x03,
+                                     0x00, 0x30, 0x00, 0x33, 0x83, 0x69, 0x9a, 0x0b, 0x2f,
+                                     0x00, 0x00, 0x00, 0x58, 0x4f, 0x80, 0x0a, 0x1c, 0x81];
+    const BOOT_CONFIRM_RESPONSE: [u8; 6] = [0x02, 0x40, 0x80, 0x01, 0x10, 0xd1];
+    const HANDSHAKE_RESPONSE: [u8; 6] = [0x02, 0x40, 0x03, 0x01, 0x00, 0x42];
+    const BROADCAST_RESPONSE: [u8; 16] = [0x02, 0xa0, 0x13, 0x0b, 0x01, 0x02, 0x01, 0x02,
+                                          0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x01, 0xb2];
+
+    const LOCK_RESPONSE: [u8; 6] = [0x02, 0xa0, 0xf9, 0x01, 0x00, 0x58];
+    const SCHEDULE_RESPONSE: [u8; 6] = [0x02, 0x40, 0x23, 0x01, 0x00, 0x62];
+
+    // Plays back canned dongle output regardless of what is transmitted,
+    // keeping everything transmitted for the test to look at.
+    #[derive(Default)]
+    struct CannedTransport {
+        rx: VecDeque<u8>,
+        tx: Arc<std::sync::Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl Transport for CannedTransport {
+        fn transmit(&mut self, data: &[u8]) -> Result<usize, DongleError> {
+            self.tx.lock().unwrap().push(data.to_vec());
+            Ok(data.len())
+        }
+
+        fn receive(&mut self, max_bytes: usize, _deadline: Instant) -> Result<Vec<u8>, DongleError> {
+            if self.rx.is_empty() {
+                return Err(DongleError::Timeout);
+            }
+            let count = max_bytes.min(self.rx.len());
+            Ok(self.rx.drain(..count).collect())
+        }
+
+        fn close(&mut self) {}
+    }
+
+    fn open_canned_recording(responses: &[&[u8]]) -> (Dongle, Arc<std::sync::Mutex<Vec<Vec<u8>>>>) {
+        let mut rx: VecDeque<u8> = VecDeque::new();
+        rx.extend(BOOT_RESPONSE);
+        rx.extend(BOOT_CONFIRM_RESPONSE);
+        for response in responses {
+            rx.extend(response.iter());
+        }
+        let transport = CannedTransport { rx, ..Default::default() };
+        let tx = transport.tx.clone();
+        (Dongle::with_transport(Box::new(transport)).unwrap(), tx)
+    }
+
+    fn open_canned(responses: &[&[u8]]) -> Dongle {
+        open_canned_recording(responses).0
+    }
+
+    #[test]
+    fn test_broadcast_before_response_is_queued() {
+        let mut dongle = open_canned(&[&BROADCAST_RESPONSE, &HANDSHAKE_RESPONSE]);
+        dongle.select_network(0x0102).unwrap();
+
+        let event = dongle.poll_event();
+        assert!(matches!(event, Some(Frame::BroadcastResponse(BroadcastResponse { network_id: 0x0102, .. }))));
+        assert!(dongle.poll_event().is_none());
+    }
+
+    #[test]
+    fn test_wait_event_times_out() {
+        let mut dongle = open_canned(&[&BROADCAST_RESPONSE]);
+        let event = dongle.wait_event(Duration::from_millis(10)).unwrap();
+        assert!(matches!(event, Some(Frame::BroadcastResponse(_))));
+        assert!(dongle.wait_event(Duration::from_millis(10)).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_send_frame() {
+        let mut dongle = open_canned(&[&HANDSHAKE_RESPONSE]);
+        dongle.send_frame(&Frame::Unknown { command: 0x4003, payload: vec![0x01, 0x02, 0x03, 0x04] }).unwrap();
+        let event = dongle.wait_event(Duration::from_millis(10)).unwrap();
+        assert!(matches!(event, Some(Frame::HandshakeResponse(_))));
+    }
+
+    #[test]
+    fn test_parse_selector() {
+        assert_eq!("1".parse::<DongleSelector>().unwrap(), DongleSelector::Index(1));
+        assert_eq!("index:0".parse::<DongleSelector>().unwrap(), DongleSelector::Index(0));
+        assert_eq!("/dev/ttyUSB0".parse::<DongleSelector>().unwrap(), DongleSelector::Path(PathBuf::from("/dev/ttyUSB0")));
+        assert_eq!("path:ttyUSB0".parse::<DongleSelector>().unwrap(), DongleSelector::Path(PathBuf::from("ttyUSB0")));
+        assert_eq!("TE000123".parse::<DongleSelector>().unwrap(), DongleSelector::Serial("TE000123".to_string()));
+        assert_eq!("serial:42".parse::<DongleSelector>().unwrap(), DongleSelector::Serial("42".to_string()));
+        assert!("index:first".parse::<DongleSelector>().is_err());
+        assert!("".parse::<DongleSelector>().is_err());
+    }
+
+    #[test]
+    fn test_builder_without_boot() {
+        let transport = CannedTransport { rx: VecDeque::from(HANDSHAKE_RESPONSE.to_vec()), ..Default::default() };
+        let mut dongle = DongleBuilder::new()
+            .boot(false)
+            .response_timeout(Duration::from_millis(10))
+            .with_transport(Box::new(transport))
+            .unwrap();
+        assert!(dongle.info().is_none());
+        dongle.select_network(0x0102).unwrap();
+        assert!(matches!(dongle.select_network(0x0102), Err(DongleError::Timeout)));
+    }
+
+    #[test]
+    fn test_commission_many_relocks_on_error() {
+        // The time update is never answered.
+        let (mut dongle, tx) = open_canned_recording(&[&LOCK_RESPONSE, &BROADCAST_RESPONSE, &LOCK_RESPONSE]);
+        dongle.response_timeout = Duration::from_millis(10);
+        let result = dongle.commission_many(Duration::from_secs(5), |_| CommissionDecision::AcceptAndStop);
+        assert!(matches!(result, Err(DongleError::Timeout)));
+
+        let tx = tx.lock().unwrap();
+        assert!(matches!(Frame::decode(&tx[2]), Ok(Frame::UnlockRequest(_))));
+        assert!(matches!(Frame::decode(&tx[3]), Ok(Frame::UpdateTimeRequest(_))));
+        assert!(matches!(Frame::decode(tx.last().unwrap()), Ok(Frame::LockRequest(_))));
+    }
+
+    #[test]
+    fn test_handshake_once_per_network() {
+        // A second handshake would swallow the second schedule response.
+        let mut dongle = open_canned(&[&HANDSHAKE_RESPONSE, &SCHEDULE_RESPONSE, &SCHEDULE_RESPONSE]);
+        dongle.response_timeout = Duration::from_millis(10);
+        dongle.switch(0x0102, 0, SwitchState::AlwaysOn).unwrap();
+        dongle.switch(0x0102, 1, SwitchState::AlwaysOff).unwrap();
+        assert_eq!(dongle.current_network, Some(0x0102));
+
+        assert!(matches!(dongle.switch(0x0102, 0, SwitchState::AlwaysOn), Err(DongleError::Timeout)));
+        assert_eq!(dongle.current_network, None);
+    }
+
+    #[test]
+    fn test_set_time_handshakes() {
+        let (mut dongle, tx) = open_canned_recording(&[&HANDSHAKE_RESPONSE]);
+        dongle.response_timeout = Duration::from_millis(10);
+        assert!(matches!(dongle.set_time(0x0102, SystemTime::now()), Err(DongleError::Timeout)));
+        assert_eq!(dongle.current_network, None);
+
+        let tx = tx.lock().unwrap();
+        assert!(matches!(Frame::decode(&tx[2]), Ok(Frame::HandshakeRequest(_))));
+        assert!(matches!(Frame::decode(&tx[3]), Ok(Frame::UpdateTimeRequest(_))));
+    }
+
+    #[test]
+    fn test_modlet_clock() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(modlet_clock(time, 0), Some(1_700_000_000));
+        assert_eq!(modlet_clock(time, 2 * 3600), Some(1_700_007_200));
+        assert_eq!(modlet_clock(time, -5 * 3600), Some(1_699_982_000));
+        assert_eq!(modlet_clock(SystemTime::UNIX_EPOCH, -1), None);
+        assert_eq!(modlet_clock(SystemTime::UNIX_EPOCH + Duration::from_secs(u32::MAX as u64), 1), None);
+
+        let mut dongle = open_canned(&[]);
+        let late = SystemTime::UNIX_EPOCH + Duration::from_secs(1 << 32);
+        let err = dongle.set_time(0x0102, late).unwrap_err();
+        assert!(matches!(err, DongleError::TimeOutOfRange(_)));
+        assert!(err.to_string().starts_with("4294967296 seconds since 1970 "));
+    }
+
+    #[test]
+    fn test_stray_magic_before_response() {
+        let mut dongle = open_canned(&[&[MESSAGE_MAGIC], &HANDSHAKE_RESPONSE]);
+        dongle.response_timeout = Duration::from_millis(10);
+        dongle.select_network(0x0102).unwrap();
+    }
+
+    #[test]
+    fn test_missing_response_times_out() {
+        let mut dongle = open_canned(&[]);
+        assert!(matches!(dongle.select_network(0x0102), Err(DongleError::Timeout)));
+    }
+
+    #[test]
+    fn test_garbled_response() {
+        let mut garbled = HANDSHAKE_RESPONSE;
+        garbled[5] ^= 0xff;
+        let mut dongle = open_canned(&[&garbled]);
+        assert!(matches!(dongle.select_network(0x0102),
+                         Err(DongleError::ChecksumMismatch { expected: 0x42, actual: 0xbd })));
     }
 }
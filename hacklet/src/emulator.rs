@@ -0,0 +1,443 @@
+use log::{debug, trace};
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use crate::error::DongleError;
+use crate::messages::*;
+use crate::power::SAMPLE_INTERVAL;
+use crate::power::WATTS_DIVISOR;
+use crate::schedule::Weekday;
+use crate::schedule::WeeklySchedule;
+use crate::transport::Transport;
+
+// Never send more samples than this in one SamplesResponse, so draining a
+// long history takes several requests like it does on real hardware.
+pub const MAX_SAMPLES_PER_RESPONSE: usize = 20;
+
+// Captured from a real dongle, apart from the device ID.
+const BOOT_DATA: [u8; 12] = [0x01, 0x00, 0x00, 0x87, 0x03, 0x00, 0x30, 0x00, 0x33, 0x83, 0x69, 0x9a];
+const BOOT_DATA2: u16 = 0x0a1c;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LoadProfile {
+    Off,
+    Constant(f64),
+    // Alternates between two loads, like a fridge compressor cycling.
+    Cycle { low: f64, high: f64, period: Duration },
+}
+
+impl LoadProfile {
+    pub fn watts_at(&self, time: u32) -> f64 {
+        match self {
+            LoadProfile::Off => 0.0,
+            LoadProfile::Constant(watts) => *watts,
+            LoadProfile::Cycle { low, high, period } => {
+                let period = period.as_secs().max(1);
+                if (time as u64 % period) < period / 2 { *high } else { *low }
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct EmulatedOutlet {
+    pub load: LoadProfile,
+    pub schedule: WeeklySchedule,
+    // Samples recorded but not yet read by the host.
+    pub stored_samples: u32,
+}
+
+impl EmulatedOutlet {
+    pub fn new(load: LoadProfile) -> EmulatedOutlet {
+        EmulatedOutlet {
+            load,
+            schedule: WeeklySchedule::always_on(),
+            stored_samples: 6,
+        }
+    }
+
+    fn sample_at(&self, time: u32) -> u16 {
+        let days = time / 86400;
+        let day = Weekday::ALL[((days + 4) % 7) as usize];
+        let minute = ((time % 86400) / 60) as u16;
+        if !self.schedule.is_on(day, minute) {
+            return 0;
+        }
+        (self.load.watts_at(time) * WATTS_DIVISOR).round().clamp(0.0, u16::MAX as f64) as u16
+    }
+}
+
+// One Modlet, which the protocol addresses by its network ID.
+#[derive(Clone, Debug)]
+pub struct EmulatedModlet {
+    pub network_id: u16,
+    pub device_id: u64,
+    pub outlets: [EmulatedOutlet; 2],
+    // The time last set by the host, and when that happened.
+    pub time: Option<(u32, Instant)>,
+    last_read: Instant,
+}
+
+impl EmulatedModlet {
+    pub fn new(network_id: u16, device_id: u64) -> EmulatedModlet {
+        EmulatedModlet {
+            network_id,
+            device_id,
+            outlets: [EmulatedOutlet::new(LoadProfile::Off), EmulatedOutlet::new(LoadProfile::Off)],
+            time: None,
+            last_read: Instant::now(),
+        }
+    }
+
+    // Without a time update the Modlet has no idea what time it is; assume
+    // its clock happens to agree with ours.
+    pub fn current_time(&self) -> u32 {
+        match self.time {
+            Some((time, set_at)) => time.wrapping_add(set_at.elapsed().as_secs() as u32),
+            None => SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|since_epoch| since_epoch.as_secs() as u32)
+                .unwrap_or(0),
+        }
+    }
+
+    fn read_samples(&mut self, channel_id: u16) -> Option<SamplesResponse> {
+        let now = self.current_time();
+        let recorded = (self.last_read.elapsed().as_secs() / SAMPLE_INTERVAL.as_secs()) as u32;
+        if recorded > 0 {
+            self.last_read = Instant::now();
+        }
+        let outlet = self.outlets.get_mut(channel_id as usize)?;
+        outlet.stored_samples = outlet.stored_samples.saturating_add(recorded);
+
+        let count = (outlet.stored_samples as usize).min(MAX_SAMPLES_PER_RESPONSE);
+        let first = now.saturating_sub(outlet.stored_samples * SAMPLE_INTERVAL.as_secs() as u32);
+        let samples: Vec<u16> = (0..count as u32)
+            .map(|index| outlet.sample_at(first + index * SAMPLE_INTERVAL.as_secs() as u32))
+            .collect();
+        outlet.stored_samples -= count as u32;
+        let stored = outlet.stored_samples.to_le_bytes();
+
+        Some(SamplesResponse {
+            network_id: self.network_id,
+            channel_id,
+            data: 0,
+            time: first,
+            sample_count: count as u8,
+            stored_sample_count: [stored[0], stored[1], stored[2]],
+            samples,
+        })
+    }
+}
+
+// The dongle side of the protocol. Requests go in as frames and whatever the
+// dongle and its Modlets would send back comes out.
+#[derive(Clone, Debug)]
+pub struct Emulator {
+    pub device_id: u64,
+    pub modlets: BTreeMap<u16, EmulatedModlet>,
+    // Modlets that will announce themselves the next time the network is
+    // unlocked.
+    pub pending: Vec<EmulatedModlet>,
+    pub unlocked: bool,
+    pub booted: bool,
+}
+
+impl Emulator {
+    pub fn new(device_id: u64) -> Emulator {
+        Emulator {
+            device_id,
+            modlets: BTreeMap::new(),
+            pending: Vec::new(),
+            unlocked: false,
+            booted: false,
+        }
+    }
+
+    pub fn add_modlet(&mut self, modlet: EmulatedModlet) {
+        self.modlets.insert(modlet.network_id, modlet);
+    }
+
+    pub fn add_pending_modlet(&mut self, modlet: EmulatedModlet) {
+        self.pending.push(modlet);
+    }
+
+    pub fn modlet(&self, network_id: u16) -> Option<&EmulatedModlet> {
+        self.modlets.get(&network_id)
+    }
+
+    pub fn handle(&mut self, request: Frame) -> Vec<Frame> {
+        trace!("Emulator handling {:x?}", request);
+        match request {
+            Frame::BootRequest(_) => {
+                self.booted = true;
+                vec![Frame::BootResponse(BootResponse {
+                    data: BOOT_DATA,
+                    device_id: self.device_id,
+                    data2: BOOT_DATA2,
+                })]
+            },
+            Frame::BootConfirmRequest(_) => vec![Frame::BootConfirmResponse(BootConfirmResponse{})],
+            Frame::UnlockRequest(_) => {
+                self.unlocked = true;
+                let mut responses = vec![Frame::LockResponse(LockResponse{})];
+                for modlet in self.pending.drain(..) {
+                    responses.push(Frame::BroadcastResponse(BroadcastResponse {
+                        network_id: modlet.network_id,
+                        device_id: modlet.device_id,
+                        data: 0,
+                    }));
+                    self.modlets.insert(modlet.network_id, modlet);
+                }
+                responses
+            },
+            Frame::LockRequest(_) => {
+                self.unlocked = false;
+                vec![Frame::LockResponse(LockResponse{})]
+            },
+            Frame::HandshakeRequest(request) if self.modlets.contains_key(&request.network_id) => {
+                vec![Frame::HandshakeResponse(HandshakeResponse{})]
+            },
+            Frame::UpdateTimeRequest(request) => match self.modlets.get_mut(&request.network_id) {
+                Some(modlet) => {
+                    modlet.time = Some((request.time, Instant::now()));
+                    vec![
+                        Frame::UpdateTimeAckResponse(UpdateTimeAckResponse{}),
+                        Frame::UpdateTimeResponse(UpdateTimeResponse { network_id: request.network_id }),
+                    ]
+                },
+                None => vec![],
+            },
+            Frame::SamplesRequest(request) => {
+                let response = self.modlets.get_mut(&request.network_id)
+                    .and_then(|modlet| modlet.read_samples(request.channel_id));
+                match response {
+                    Some(response) => vec![Frame::AckResponse(AckResponse{}), Frame::SamplesResponse(response)],
+                    None => vec![],
+                }
+            },
+            Frame::ScheduleRequest(request) => {
+                let outlet = self.modlets.get_mut(&request.network_id)
+                    .and_then(|modlet| modlet.outlets.get_mut(request.channel_id as usize));
+                match outlet {
+                    Some(outlet) => {
+                        outlet.schedule = WeeklySchedule::decode(&request.schedule);
+                        vec![Frame::ScheduleResponse(ScheduleResponse{})]
+                    },
+                    None => vec![],
+                }
+            },
+            other => {
+                debug!("Emulator ignoring {:x?}", other);
+                vec![]
+            },
+        }
+    }
+}
+
+// Lets a Dongle talk to an Emulator. The emulator is shared so tests can
+// look at and change its state while the dongle owns the transport.
+pub struct EmulatedTransport {
+    pub emulator: Arc<Mutex<Emulator>>,
+    reader: FrameReader,
+    rx: VecDeque<u8>,
+}
+
+impl EmulatedTransport {
+    pub fn new(emulator: Arc<Mutex<Emulator>>) -> EmulatedTransport {
+        EmulatedTransport {
+            emulator,
+            reader: FrameReader::new(),
+            rx: VecDeque::new(),
+        }
+    }
+}
+
+impl Transport for EmulatedTransport {
+    fn transmit(&mut self, data: &[u8]) -> Result<usize, DongleError> {
+        trace!("TX: {:x?}", data);
+        self.reader.push(data);
+        while let Some(buf) = self.reader.next_frame() {
+            let request = Frame::decode(&buf)?;
+            let responses = self.emulator.lock().unwrap().handle(request);
+            for response in responses {
+                self.rx.extend(response.encode()?);
+            }
+        }
+        Ok(data.len())
+    }
+
+    fn receive(&mut self, max_bytes: usize, deadline: Instant) -> Result<Vec<u8>, DongleError> {
+        if self.rx.is_empty() {
+            // Nothing else can produce data while we wait, so just let the
+            // deadline pass like a silent device would.
+            std::thread::sleep(deadline.saturating_duration_since(Instant::now()));
+            return Err(DongleError::Timeout);
+        }
+        let count = max_bytes.min(self.rx.len());
+        let bytes: Vec<u8> = self.rx.drain(..count).collect();
+        trace!("RX: {:x?}", bytes);
+        Ok(bytes)
+    }
+
+    fn close(&mut self) {
+        debug!("Closing emulated connection");
+    }
+}
+
+#[cfg(test)]
+mod test_emulator {
+    use super::*;
+    use crate::dongle::{CommissionDecision, CommissionStatus, Dongle, DongleBuilder, DongleId, SwitchState};
+
+    fn open_emulated(emulator: Emulator) -> (Dongle, Arc<Mutex<Emulator>>) {
+        let emulator = Arc::new(Mutex::new(emulator));
+        let transport = EmulatedTransport::new(emulator.clone());
+        (Dongle::with_transport(Box::new(transport)).unwrap(), emulator)
+    }
+
+    #[test]
+    fn test_boot() {
+        let (dongle, emulator) = open_emulated(Emulator::new(0x0b2f000000584f80));
+        assert!(emulator.lock().unwrap().booted);
+        let info = dongle.info().unwrap();
+        assert_eq!(info.device_id, 0x0b2f000000584f80);
+        assert_eq!(info.raw_boot_data, BOOT_DATA);
+        assert_eq!(info.raw_boot_data2, BOOT_DATA2);
+    }
+
+    #[test]
+    fn test_switch_and_schedule() {
+        let mut emulator = Emulator::new(1);
+        emulator.add_modlet(EmulatedModlet::new(0x215a, 0x1234));
+        let (mut dongle, emulator) = open_emulated(emulator);
+
+        dongle.switch(0x215a, 1, SwitchState::AlwaysOff).unwrap();
+        assert_eq!(emulator.lock().unwrap().modlet(0x215a).unwrap().outlets[1].schedule, WeeklySchedule::always_off());
+
+        // The emulator decodes with the same guessed layout, so this only
+        // checks that the schedule makes it through, not the layout itself.
+        let schedule: WeeklySchedule = "Mon-Fri 07:00-09:00".parse().unwrap();
+        dongle.set_schedule(0x215a, 0, &schedule).unwrap();
+        assert_eq!(emulator.lock().unwrap().modlet(0x215a).unwrap().outlets[0].schedule, schedule);
+    }
+
+    #[test]
+    fn test_sync_time() {
+        let mut emulator = Emulator::new(1);
+        emulator.add_modlet(EmulatedModlet::new(0x215a, 0x1234));
+        let emulator = Arc::new(Mutex::new(emulator));
+        let mut dongle = DongleBuilder::new()
+            .utc_offset(3600)
+            .with_transport(Box::new(EmulatedTransport::new(emulator.clone())))
+            .unwrap();
+
+        let now = SystemTime::now();
+        dongle.sync_time(0x215a).unwrap();
+        let (clock, _) = emulator.lock().unwrap().modlet(0x215a).unwrap().time.unwrap();
+        let expected = now.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() + 3600;
+        assert!((clock as u64).abs_diff(expected) <= 1);
+
+        // Six stored samples, the first one a minute ago in UTC.
+        let batch = dongle.read_power(0x215a, 0).unwrap();
+        let age = now.duration_since(batch.time).unwrap();
+        assert!(age >= Duration::from_secs(59) && age <= Duration::from_secs(61));
+    }
+
+    #[test]
+    fn test_read_history() {
+        let mut modlet = EmulatedModlet::new(0x215a, 0x1234);
+        modlet.outlets[0] = EmulatedOutlet::new(LoadProfile::Constant(60.0));
+        modlet.outlets[0].stored_samples = 45;
+        let mut emulator = Emulator::new(1);
+        emulator.add_modlet(modlet);
+        let (mut dongle, _) = open_emulated(emulator);
+
+        let history = dongle.read_history(0x215a, 0).unwrap();
+        assert_eq!(history.readings.len(), 45);
+        assert!(history.gaps.is_empty());
+        assert!(history.readings.iter().all(|reading| reading.watts == 60.0));
+    }
+
+    #[test]
+    fn test_commission() {
+        let mut emulator = Emulator::new(1);
+        emulator.add_pending_modlet(EmulatedModlet::new(0x3b1c, 0x0102030405060708));
+        let (mut dongle, emulator) = open_emulated(emulator);
+
+        let status = dongle.commission().unwrap();
+        assert!(matches!(status, CommissionStatus::Commissioned(id) if id.network == 0x3b1c && id.device == 0x0102030405060708));
+        let emulator = emulator.lock().unwrap();
+        assert!(!emulator.unlocked);
+        assert!(emulator.modlet(0x3b1c).unwrap().time.is_some());
+    }
+
+    #[test]
+    fn test_commission_times_out() {
+        let (mut dongle, emulator) = open_emulated(Emulator::new(1));
+        let started = Instant::now();
+        let status = dongle.commission_within(Duration::from_millis(300)).unwrap();
+        assert!(matches!(status, CommissionStatus::NotCommissioned));
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(!emulator.lock().unwrap().unlocked);
+    }
+
+    #[test]
+    fn test_commission_many() {
+        let mut emulator = Emulator::new(1);
+        // The first Modlet broadcasts twice.
+        emulator.add_pending_modlet(EmulatedModlet::new(0x3b1c, 0x0102030405060708));
+        emulator.add_pending_modlet(EmulatedModlet::new(0x3b1c, 0x0102030405060708));
+        emulator.add_pending_modlet(EmulatedModlet::new(0x4c2d, 0x1112131415161718));
+        let (mut dongle, emulator) = open_emulated(emulator);
+
+        let mut offered = Vec::new();
+        let accepted = dongle.commission_many(Duration::from_millis(300), |id| {
+            offered.push(id.network);
+            if id.network == 0x3b1c { CommissionDecision::Accept } else { CommissionDecision::Reject }
+        }).unwrap();
+
+        assert_eq!(offered, vec![0x3b1c, 0x4c2d]);
+        assert_eq!(accepted, vec![DongleId { device: 0x0102030405060708, network: 0x3b1c }]);
+        let emulator = emulator.lock().unwrap();
+        assert!(!emulator.unlocked);
+        assert!(emulator.modlet(0x3b1c).unwrap().time.is_some());
+        assert!(emulator.modlet(0x4c2d).unwrap().time.is_none());
+    }
+
+    #[test]
+    fn test_commission_many_stops() {
+        let mut emulator = Emulator::new(1);
+        emulator.add_pending_modlet(EmulatedModlet::new(0x3b1c, 0x0102030405060708));
+        emulator.add_pending_modlet(EmulatedModlet::new(0x4c2d, 0x1112131415161718));
+        let (mut dongle, emulator) = open_emulated(emulator);
+
+        let accepted = dongle.commission_many(Duration::from_secs(30), |_| CommissionDecision::AcceptAndStop).unwrap();
+        assert_eq!(accepted.len(), 1);
+        assert!(!emulator.lock().unwrap().unlocked);
+
+        // Nobody else is waiting to join, so only the interrupt ends this.
+        let started = Instant::now();
+        let flag = dongle.interrupt_flag();
+        let interrupter = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            flag.store(true, std::sync::atomic::Ordering::SeqCst);
+        });
+        let accepted = dongle.commission_many(Duration::from_secs(30), |_| CommissionDecision::Stop).unwrap();
+        interrupter.join().unwrap();
+        assert!(accepted.is_empty());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(!emulator.lock().unwrap().unlocked);
+
+        // The interrupt stays set until the caller clears it.
+        let started = Instant::now();
+        let accepted = dongle.commission_many(Duration::from_secs(30), |_| CommissionDecision::Accept).unwrap();
+        assert!(accepted.is_empty());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
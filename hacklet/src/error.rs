@@ -0,0 +1,99 @@
+use std::fmt;
+use std::time::SystemTime;
+
+#[derive(Debug)]
+pub enum DongleError {
+    // The Modlet did not respond before the deadline
+    Timeout,
+    ChecksumMismatch { expected: u8, actual: u8 },
+    UnexpectedCommand { expected: u16, got: u16 },
+    // The message ended before all of its fields could be read
+    Truncated,
+    MalformedMessage(binrw::Error),
+    DeviceNotFound,
+    InvalidSchedule(String),
+    // Text that was meant to describe something, such as a capture line or a
+    // dongle selector, but doesn't
+    InvalidInput(String),
+    // The Modlet clock counts unsigned 32-bit seconds, from 1970 to 2106
+    TimeOutOfRange(SystemTime),
+    Unsupported(String),
+    #[cfg(feature = "d2xx")]
+    Ftdi(libftd2xx::FtStatus),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for DongleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DongleError::Timeout => write!(f, "Modlet did not respond"),
+            DongleError::ChecksumMismatch { expected, actual } => {
+                write!(f, "message checksum {:#04x} does not match calculated {:#04x}", actual, expected)
+            },
+            DongleError::UnexpectedCommand { expected, got } => {
+                write!(f, "expected message {:#06x} but received {:#06x}", expected, got)
+            },
+            DongleError::Truncated => write!(f, "message was cut short"),
+            DongleError::MalformedMessage(err) => write!(f, "malformed message: {}", err),
+            DongleError::DeviceNotFound => write!(f, "no Modlet dongle found"),
+            DongleError::InvalidSchedule(reason) => write!(f, "invalid schedule: {}", reason),
+            DongleError::InvalidInput(reason) => write!(f, "invalid input: {}", reason),
+            DongleError::TimeOutOfRange(time) => {
+                let seconds = match time.duration_since(SystemTime::UNIX_EPOCH) {
+                    Ok(since) => since.as_secs() as i128,
+                    Err(err) => -(err.duration().as_secs() as i128),
+                };
+                write!(f, "{} seconds since 1970 is outside the Modlet clock range of 1970 to 2106", seconds)
+            },
+            DongleError::Unsupported(what) => write!(f, "{}", what),
+            #[cfg(feature = "d2xx")]
+            DongleError::Ftdi(status) => write!(f, "FTDI driver error: {}", status),
+            DongleError::Io(err) => write!(f, "serial connection error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for DongleError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DongleError::MalformedMessage(err) => Some(err),
+            #[cfg(feature = "d2xx")]
+            DongleError::Ftdi(status) => Some(status),
+            DongleError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<binrw::Error> for DongleError {
+    fn from(err: binrw::Error) -> Self {
+        match err {
+            binrw::Error::Io(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => DongleError::Truncated,
+            binrw::Error::Io(err) => DongleError::Io(err),
+            err => DongleError::MalformedMessage(err),
+        }
+    }
+}
+
+impl From<std::io::Error> for DongleError {
+    fn from(err: std::io::Error) -> Self {
+        DongleError::Io(err)
+    }
+}
+
+#[cfg(all(unix, feature = "tty"))]
+impl From<nix::Error> for DongleError {
+    fn from(err: nix::Error) -> Self {
+        DongleError::Io(err.into())
+    }
+}
+
+#[cfg(feature = "d2xx")]
+impl From<libftd2xx::FtStatus> for DongleError {
+    fn from(status: libftd2xx::FtStatus) -> Self {
+        match status {
+            libftd2xx::FtStatus::DEVICE_NOT_FOUND => DongleError::DeviceNotFound,
+            status => DongleError::Ftdi(status),
+        }
+    }
+}
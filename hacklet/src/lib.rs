@@ -1,3 +1,12 @@
+pub mod capture;
 pub mod dongle;
-mod messages;
-mod serial_connection;
\ No newline at end of file
+pub mod emulator;
+mod error;
+pub mod messages;
+pub mod power;
+pub mod schedule;
+#[cfg(feature = "d2xx")]
+pub mod serial_connection;
+pub mod transport;
+#[cfg(all(unix, feature = "tty"))]
+pub mod tty_connection;
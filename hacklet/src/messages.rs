@@ -1,3 +1,5 @@
+use log::{debug, trace};
+
 use binrw::binrw;
 use binrw::BinRead;
 use binrw::BinWrite;
@@ -8,15 +10,15 @@ use binrw::io::Write;
 use binrw::meta::ReadEndian;
 use binrw::meta::WriteEndian;
 
+use crate::error::DongleError;
+
 pub fn create_message_buf<T>(message: &T) -> Result<Vec<u8>, binrw::Error>
 where
     T: for<'a> BinWrite<Args<'a> = ()> + WriteEndian + PartialEq
 {
     let mut data = binrw::io::Cursor::new(vec![]);
-    match message.write(&mut data) {
-        Ok(()) => Ok(data.into_inner()),
-        Err(err) => Err(err),
-    }
+    message.write(&mut data)?;
+    Ok(data.into_inner())
 }
 
 pub fn read_message_from_buf<T>(buf: &[u8]) -> Result<T, binrw::Error>
@@ -50,14 +52,11 @@ impl<T> MessageChecksum<T> {
 
 impl<T: Read> Read for MessageChecksum<T> {
     fn read(&mut self, buf: &mut [u8]) -> binrw::io::Result<usize> {
-        let size = match self.wrapped_stream.read(buf) {
-            Ok(size) => size,
-            Err(error) => return Err(error),
-        };
+        let size = self.wrapped_stream.read(buf)?;
 
         for byte in &buf[0..size] {
             self.checksum = self.previous_checksum;
-            self.previous_checksum = self.previous_checksum ^ byte;
+            self.previous_checksum ^= byte;
         }
 
         Ok(size)
@@ -72,13 +71,10 @@ impl<T: Seek> Seek for MessageChecksum<T> {
 
 impl<T: Write> Write for MessageChecksum<T> {
     fn write(&mut self, buf: &[u8]) -> binrw::io::Result<usize> {
-        let size = match self.wrapped_stream.write(buf) {
-            Ok(size) => size,
-            Err(error) => return Err(error),
-        };
+        let size = self.wrapped_stream.write(buf)?;
 
         for byte in &buf[0..size] {
-            self.checksum = self.checksum ^ byte;
+            self.checksum ^= byte;
         }
 
         Ok(size)
@@ -89,6 +85,157 @@ impl<T: Write> Write for MessageChecksum<T> {
     }
 }
 
+pub const MESSAGE_MAGIC: u8 = 0x02;
+
+// Magic byte, two byte command ID and one byte payload length.
+pub const MESSAGE_HEADER_LENGTH: usize = 4;
+
+// Checks the framing, checksum and command ID before handing the message to
+// binrw, so callers learn which of those was wrong rather than a generic
+// assertion failure.
+pub fn decode_message<T>(buf: &[u8]) -> Result<T, DongleError>
+where
+    T: Message + for<'a> BinRead<Args<'a> = ()> + ReadEndian + PartialEq
+{
+    check_framing(buf)?;
+    let command = u16::from_be_bytes([buf[1], buf[2]]);
+    if command != T::COMMAND {
+        return Err(DongleError::UnexpectedCommand { expected: T::COMMAND, got: command });
+    }
+    Ok(read_message_from_buf::<T>(buf)?)
+}
+
+fn check_framing(buf: &[u8]) -> Result<(), DongleError> {
+    if buf.len() < MESSAGE_HEADER_LENGTH + 1 {
+        return Err(DongleError::Truncated);
+    }
+    if buf[0] != MESSAGE_MAGIC {
+        return Err(binrw::Error::BadMagic { pos: 0, found: Box::new(buf[0]) }.into());
+    }
+    let frame_length = MESSAGE_HEADER_LENGTH + buf[3] as usize + 1;
+    if buf.len() < frame_length {
+        return Err(DongleError::Truncated);
+    }
+    if buf.len() > frame_length {
+        return Err(binrw::Error::AssertFail {
+            pos: frame_length as u64,
+            message: format!("{:?} unexpected bytes after the checksum", buf.len() - frame_length),
+        }.into());
+    }
+    let expected = message_checksum(&buf[1..frame_length-1]);
+    let actual = buf[frame_length-1];
+    if expected != actual {
+        return Err(DongleError::ChecksumMismatch { expected, actual });
+    }
+    Ok(())
+}
+
+// The same running XOR that MessageChecksum computes, over everything
+// between the magic byte and the checksum byte.
+pub fn message_checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |checksum, byte| checksum ^ byte)
+}
+
+// Splits a raw byte stream from the dongle into whole messages. Anything that
+// does not start with the magic byte, or does not pass its checksum, is
+// thrown away one byte at a time until the stream lines up again.
+#[derive(Default)]
+pub struct FrameReader {
+    buffer: Vec<u8>,
+    // The last checksum that didn't match, as (expected, actual), so a
+    // response that only ever arrived garbled can be told from none at all.
+    bad_checksum: Option<(u8, u8)>,
+}
+
+impl FrameReader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    // The number of bytes that can safely be read without running past the
+    // end of the frame currently being assembled.
+    pub fn bytes_wanted(&self) -> usize {
+        if self.buffer.len() < MESSAGE_HEADER_LENGTH || self.buffer[0] != MESSAGE_MAGIC {
+            return MESSAGE_HEADER_LENGTH.saturating_sub(self.buffer.len()).max(1);
+        }
+        let frame_length = MESSAGE_HEADER_LENGTH + self.buffer[3] as usize + 1;
+        frame_length.saturating_sub(self.buffer.len()).max(1)
+    }
+
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        loop {
+            match self.buffer.iter().position(|byte| *byte == MESSAGE_MAGIC) {
+                Some(0) => (),
+                Some(start) => {
+                    debug!("Discarding {:?} bytes before message start", start);
+                    trace!("Discarded: {:x?}", &self.buffer[..start]);
+                    self.buffer.drain(..start);
+                },
+                None => {
+                    if !self.buffer.is_empty() {
+                        debug!("Discarding {:?} bytes with no message start", self.buffer.len());
+                        trace!("Discarded: {:x?}", self.buffer);
+                        self.buffer.clear();
+                    }
+                    return None;
+                },
+            }
+
+            if self.buffer.len() < MESSAGE_HEADER_LENGTH {
+                return None;
+            }
+            let frame_length = MESSAGE_HEADER_LENGTH + self.buffer[3] as usize + 1;
+            if self.buffer.len() < frame_length {
+                // A stray magic byte would otherwise hold up a whole frame
+                // that has already arrived behind it.
+                let later = (1..self.buffer.len())
+                    .find(|start| self.buffer[*start] == MESSAGE_MAGIC && self.complete_frame_at(*start).is_some());
+                if let Some(start) = later {
+                    debug!("Discarding {:?} bytes of an incomplete message", start);
+                    trace!("Discarded: {:x?}", &self.buffer[..start]);
+                    self.buffer.drain(..start);
+                    continue;
+                }
+                return None;
+            }
+
+            let expected = message_checksum(&self.buffer[1..frame_length-1]);
+            let actual = self.buffer[frame_length-1];
+            if expected != actual {
+                debug!("Bad checksum {:#04x} (expected {:#04x}), resynchronising", actual, expected);
+                self.bad_checksum = Some((expected, actual));
+                self.buffer.drain(..1);
+                continue;
+            }
+
+            return Some(self.buffer.drain(..frame_length).collect());
+        }
+    }
+
+    // Returns and forgets the last checksum failure since this was last called.
+    pub fn take_bad_checksum(&mut self) -> Option<(u8, u8)> {
+        self.bad_checksum.take()
+    }
+
+    // The length of the frame starting at start, if all of it is in the
+    // buffer and its checksum is right.
+    fn complete_frame_at(&self, start: usize) -> Option<usize> {
+        let frame = &self.buffer[start..];
+        if frame.len() < MESSAGE_HEADER_LENGTH {
+            return None;
+        }
+        let frame_length = MESSAGE_HEADER_LENGTH + frame[3] as usize + 1;
+        if frame.len() < frame_length || message_checksum(&frame[1..frame_length-1]) != frame[frame_length-1] {
+            return None;
+        }
+        Some(frame_length)
+    }
+}
+
 #[binrw]
 #[brw(big, magic = 0x02u8)]
 #[brw(stream = s, map_stream = MessageChecksum::new)]
@@ -228,7 +375,7 @@ pub struct SamplesResponse {
     #[brw(little)] pub time: u32,
     pub sample_count: u8,
     pub stored_sample_count: [u8; 3],
-    #[br(little, args { count: sample_count as usize})] pub samples: Vec<u16>,
+    #[brw(little)] #[br(args { count: sample_count as usize })] pub samples: Vec<u16>,
     #[bw(calc(s.checksum))] checksum: u8,
 }
 
@@ -273,6 +420,9 @@ pub struct BootConfirmRequest {
     #[bw(calc(w.checksum))] checksum: u8
 }
 
+// Unlock and lock are the only payloads of 0xa236 seen from the original
+// software. None of them is known to remove a device from a network, so
+// there is no decommission message.
 #[binrw]
 #[brw(big, magic = 0x02u8)]
 #[brw(stream = w, map_stream = MessageChecksum::new)]
@@ -314,7 +464,7 @@ pub struct UpdateTimeRequest {
     #[bw(calc(0x4022))] command: u16,
     #[bw(calc(0x06))] payload_length: u8,
     pub network_id: u16,
-    #[bw(little)]
+    #[brw(little)]
     pub time: u32,
     #[bw(calc(w.checksum))] pub checksum: u8,
 }
@@ -368,6 +518,186 @@ pub struct ScheduleRequest {
     #[bw(calc(w.checksum))] checksum: u8,
 }
 
+// The command ID that identifies each message on the wire. Several requests
+// and their responses share an ID and are told apart by their length.
+pub trait Message {
+    const COMMAND: u16;
+}
+
+macro_rules! message_commands {
+    ($($message:ident => $command:expr),* $(,)?) => {
+        $(impl Message for $message {
+            const COMMAND: u16 = $command;
+        })*
+    };
+}
+
+message_commands! {
+    BootResponse => 0x4084,
+    BootConfirmResponse => 0x4080,
+    BroadcastResponse => 0xa013,
+    LockResponse => 0xa0f9,
+    UpdateTimeAckResponse => 0x4022,
+    UpdateTimeResponse => 0x40a2,
+    HandshakeResponse => 0x4003,
+    AckResponse => 0x4024,
+    SamplesResponse => 0x40a4,
+    ScheduleResponse => 0x4023,
+    BootRequest => 0x4004,
+    BootConfirmRequest => 0x4000,
+    UnlockRequest => 0xa236,
+    LockRequest => 0xa236,
+    UpdateTimeRequest => 0x4022,
+    HandshakeRequest => 0x4003,
+    SamplesRequest => 0x4024,
+    ScheduleRequest => 0x4023,
+}
+
+// Any single message in either direction, decoded without knowing ahead of
+// time what to expect. Well-formed messages that don't match one of the
+// known structures are kept as Unknown rather than rejected.
+#[derive(Debug, PartialEq)]
+pub enum Frame {
+    BootResponse(BootResponse),
+    BootConfirmResponse(BootConfirmResponse),
+    BroadcastResponse(BroadcastResponse),
+    LockResponse(LockResponse),
+    UpdateTimeAckResponse(UpdateTimeAckResponse),
+    UpdateTimeResponse(UpdateTimeResponse),
+    HandshakeResponse(HandshakeResponse),
+    AckResponse(AckResponse),
+    SamplesResponse(SamplesResponse),
+    ScheduleResponse(ScheduleResponse),
+    BootRequest(BootRequest),
+    BootConfirmRequest(BootConfirmRequest),
+    UnlockRequest(UnlockRequest),
+    LockRequest(LockRequest),
+    UpdateTimeRequest(UpdateTimeRequest),
+    HandshakeRequest(HandshakeRequest),
+    SamplesRequest(SamplesRequest),
+    ScheduleRequest(ScheduleRequest),
+    Unknown { command: u16, payload: Vec<u8> },
+}
+
+impl Frame {
+    pub fn decode(buf: &[u8]) -> Result<Frame, DongleError> {
+        check_framing(buf)?;
+        let frame_length = buf.len();
+
+        let command = u16::from_be_bytes([buf[1], buf[2]]);
+        let frame = match (command, buf[3]) {
+            (BootResponse::COMMAND, 0x16) => read_message_from_buf(buf).map(Frame::BootResponse),
+            (BootConfirmResponse::COMMAND, 0x01) => read_message_from_buf(buf).map(Frame::BootConfirmResponse),
+            (BroadcastResponse::COMMAND, 0x0b) => read_message_from_buf(buf).map(Frame::BroadcastResponse),
+            (LockResponse::COMMAND, 0x01) => read_message_from_buf(buf).map(Frame::LockResponse),
+            (UpdateTimeAckResponse::COMMAND, 0x01) => read_message_from_buf(buf).map(Frame::UpdateTimeAckResponse),
+            (UpdateTimeResponse::COMMAND, 0x03) => read_message_from_buf(buf).map(Frame::UpdateTimeResponse),
+            (HandshakeResponse::COMMAND, 0x01) => read_message_from_buf(buf).map(Frame::HandshakeResponse),
+            (AckResponse::COMMAND, 0x01) => read_message_from_buf(buf).map(Frame::AckResponse),
+            (SamplesResponse::COMMAND, _) => read_message_from_buf(buf).map(Frame::SamplesResponse),
+            (ScheduleResponse::COMMAND, 0x01) => read_message_from_buf(buf).map(Frame::ScheduleResponse),
+            (BootRequest::COMMAND, 0x00) => read_message_from_buf(buf).map(Frame::BootRequest),
+            (BootConfirmRequest::COMMAND, 0x00) => read_message_from_buf(buf).map(Frame::BootConfirmRequest),
+            (UnlockRequest::COMMAND, 0x04) => read_message_from_buf(buf).map(Frame::UnlockRequest)
+                .or_else(|_| read_message_from_buf(buf).map(Frame::LockRequest)),
+            (UpdateTimeRequest::COMMAND, 0x06) => read_message_from_buf(buf).map(Frame::UpdateTimeRequest),
+            (HandshakeRequest::COMMAND, 0x04) => read_message_from_buf(buf).map(Frame::HandshakeRequest),
+            (SamplesRequest::COMMAND, 0x06) => read_message_from_buf(buf).map(Frame::SamplesRequest),
+            (ScheduleRequest::COMMAND, 0x3b) => read_message_from_buf(buf).map(Frame::ScheduleRequest),
+            _ => Err(binrw::Error::NoVariantMatch { pos: 1 }),
+        };
+
+        Ok(frame.unwrap_or_else(|_| Frame::Unknown {
+            command,
+            payload: buf[MESSAGE_HEADER_LENGTH..frame_length-1].to_vec(),
+        }))
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, DongleError> {
+        let buf = match self {
+            Frame::BootResponse(message) => create_message_buf(message),
+            Frame::BootConfirmResponse(message) => create_message_buf(message),
+            Frame::BroadcastResponse(message) => create_message_buf(message),
+            Frame::LockResponse(message) => create_message_buf(message),
+            Frame::UpdateTimeAckResponse(message) => create_message_buf(message),
+            Frame::UpdateTimeResponse(message) => create_message_buf(message),
+            Frame::HandshakeResponse(message) => create_message_buf(message),
+            Frame::AckResponse(message) => create_message_buf(message),
+            Frame::SamplesResponse(message) => create_message_buf(message),
+            Frame::ScheduleResponse(message) => create_message_buf(message),
+            Frame::BootRequest(message) => create_message_buf(message),
+            Frame::BootConfirmRequest(message) => create_message_buf(message),
+            Frame::UnlockRequest(message) => create_message_buf(message),
+            Frame::LockRequest(message) => create_message_buf(message),
+            Frame::UpdateTimeRequest(message) => create_message_buf(message),
+            Frame::HandshakeRequest(message) => create_message_buf(message),
+            Frame::SamplesRequest(message) => create_message_buf(message),
+            Frame::ScheduleRequest(message) => create_message_buf(message),
+            Frame::Unknown { command, payload } => {
+                let payload_length = u8::try_from(payload.len()).map_err(|_| binrw::Error::AssertFail {
+                    pos: 3,
+                    message: format!("payload of {:?} bytes is too long", payload.len()),
+                })?;
+                let mut buf = vec![MESSAGE_MAGIC];
+                buf.extend_from_slice(&command.to_be_bytes());
+                buf.push(payload_length);
+                buf.extend_from_slice(payload);
+                buf.push(message_checksum(&buf[1..]));
+                Ok(buf)
+            },
+        };
+        Ok(buf?)
+    }
+
+    pub fn command(&self) -> u16 {
+        match self {
+            Frame::BootResponse(_) => BootResponse::COMMAND,
+            Frame::BootConfirmResponse(_) => BootConfirmResponse::COMMAND,
+            Frame::BroadcastResponse(_) => BroadcastResponse::COMMAND,
+            Frame::LockResponse(_) => LockResponse::COMMAND,
+            Frame::UpdateTimeAckResponse(_) => UpdateTimeAckResponse::COMMAND,
+            Frame::UpdateTimeResponse(_) => UpdateTimeResponse::COMMAND,
+            Frame::HandshakeResponse(_) => HandshakeResponse::COMMAND,
+            Frame::AckResponse(_) => AckResponse::COMMAND,
+            Frame::SamplesResponse(_) => SamplesResponse::COMMAND,
+            Frame::ScheduleResponse(_) => ScheduleResponse::COMMAND,
+            Frame::BootRequest(_) => BootRequest::COMMAND,
+            Frame::BootConfirmRequest(_) => BootConfirmRequest::COMMAND,
+            Frame::UnlockRequest(_) => UnlockRequest::COMMAND,
+            Frame::LockRequest(_) => LockRequest::COMMAND,
+            Frame::UpdateTimeRequest(_) => UpdateTimeRequest::COMMAND,
+            Frame::HandshakeRequest(_) => HandshakeRequest::COMMAND,
+            Frame::SamplesRequest(_) => SamplesRequest::COMMAND,
+            Frame::ScheduleRequest(_) => ScheduleRequest::COMMAND,
+            Frame::Unknown { command, .. } => *command,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Frame::BootResponse(_) => "BootResponse",
+            Frame::BootConfirmResponse(_) => "BootConfirmResponse",
+            Frame::BroadcastResponse(_) => "BroadcastResponse",
+            Frame::LockResponse(_) => "LockResponse",
+            Frame::UpdateTimeAckResponse(_) => "UpdateTimeAckResponse",
+            Frame::UpdateTimeResponse(_) => "UpdateTimeResponse",
+            Frame::HandshakeResponse(_) => "HandshakeResponse",
+            Frame::AckResponse(_) => "AckResponse",
+            Frame::SamplesResponse(_) => "SamplesResponse",
+            Frame::ScheduleResponse(_) => "ScheduleResponse",
+            Frame::BootRequest(_) => "BootRequest",
+            Frame::BootConfirmRequest(_) => "BootConfirmRequest",
+            Frame::UnlockRequest(_) => "UnlockRequest",
+            Frame::LockRequest(_) => "LockRequest",
+            Frame::UpdateTimeRequest(_) => "UpdateTimeRequest",
+            Frame::HandshakeRequest(_) => "HandshakeRequest",
+            Frame::SamplesRequest(_) => "SamplesRequest",
+            Frame::ScheduleRequest(_) => "ScheduleRequest",
+            Frame::Unknown { .. } => "Unknown",
+        }
+    }
+}
+
 // Test checksum calculations for all messages.
 #[cfg(test)]
 mod test_message_checksums {
@@ -396,12 +726,13 @@ mod test_message_checksums {
         let expected_bytes = create_message_buf(known_good).unwrap();
         let extracted_bytes = create_message_buf(&test_message).unwrap();
         assert_eq!(expected_bytes, extracted_bytes);
+        assert_eq!(expected_bytes, test_data);
     }
 
     fn get_test_data_copy(test_data: &[u8]) -> Vec<u8> {
         let mut copied_data = vec![0; test_data.len()];
         copied_data.copy_from_slice(test_data);
-        return copied_data;
+        copied_data
     }
 
     fn test_bad_data_checksum_failure<T>(test_data: &[u8])
@@ -432,8 +763,8 @@ mod test_message_checksums {
         let command = [poison_data[1], poison_data[2]];
 
         let checksum = poison_data.last_mut().expect("Expected test data to not be empty");
-        *checksum = *checksum ^ command[0];
-        *checksum = *checksum ^ command[1];
+        *checksum ^= command[0];
+        *checksum ^= command[1];
 
         let test_message_result = read_message_from_buf::<T>(&poison_data);
         assert!(test_message_result.is_err_and(|err| err.to_string().contains("command")));
@@ -649,3 +980,156 @@ mod test_message_checksums {
         test_bad_command_header_failure::<ScheduleRequest>(&test_data);
     }
 }
+
+
+#[cfg(test)]
+mod test_frame_reader {
+    use super::*;
+
+    const LOCK_RESPONSE: [u8; 6] = [0x02, 0xa0, 0xf9, 0x01, 0x00, 0x58];
+    const ACK_RESPONSE: [u8; 6] = [0x02, 0x40, 0x24, 0x01, 0x00, 0x65];
+
+    #[test]
+    fn test_aligned_frames() {
+        let mut reader = FrameReader::new();
+        reader.push(&LOCK_RESPONSE);
+        reader.push(&ACK_RESPONSE);
+        assert_eq!(reader.next_frame(), Some(LOCK_RESPONSE.to_vec()));
+        assert_eq!(reader.next_frame(), Some(ACK_RESPONSE.to_vec()));
+        assert_eq!(reader.next_frame(), None);
+    }
+
+    #[test]
+    fn test_split_frame() {
+        let mut reader = FrameReader::new();
+        assert_eq!(reader.bytes_wanted(), 4);
+        reader.push(&LOCK_RESPONSE[..2]);
+        assert_eq!(reader.next_frame(), None);
+        assert_eq!(reader.bytes_wanted(), 2);
+        reader.push(&LOCK_RESPONSE[2..4]);
+        assert_eq!(reader.next_frame(), None);
+        assert_eq!(reader.bytes_wanted(), 2);
+        reader.push(&LOCK_RESPONSE[4..]);
+        assert_eq!(reader.next_frame(), Some(LOCK_RESPONSE.to_vec()));
+        assert_eq!(reader.bytes_wanted(), 4);
+    }
+
+    #[test]
+    fn test_garbage_before_frame() {
+        let mut reader = FrameReader::new();
+        reader.push(&[0xff, 0x13, 0x00]);
+        assert_eq!(reader.next_frame(), None);
+        reader.push(&LOCK_RESPONSE);
+        assert_eq!(reader.next_frame(), Some(LOCK_RESPONSE.to_vec()));
+    }
+
+    #[test]
+    fn test_stray_magic_before_frame() {
+        const HANDSHAKE_RESPONSE: [u8; 6] = [0x02, 0x40, 0x03, 0x01, 0x00, 0x42];
+        let mut reader = FrameReader::new();
+        reader.push(&[MESSAGE_MAGIC]);
+        reader.push(&HANDSHAKE_RESPONSE);
+        assert_eq!(reader.next_frame(), Some(HANDSHAKE_RESPONSE.to_vec()));
+        assert_eq!(reader.next_frame(), None);
+        assert_eq!(reader.bytes_wanted(), 4);
+    }
+
+    #[test]
+    fn test_bad_checksum_resync() {
+        let mut corrupted = LOCK_RESPONSE;
+        corrupted[4] = 0x01;
+
+        let mut reader = FrameReader::new();
+        reader.push(&corrupted);
+        reader.push(&ACK_RESPONSE);
+        assert_eq!(reader.next_frame(), Some(ACK_RESPONSE.to_vec()));
+        assert_eq!(reader.next_frame(), None);
+        assert_eq!(reader.take_bad_checksum(), Some((0x59, 0x58)));
+        assert_eq!(reader.take_bad_checksum(), None);
+    }
+
+    #[test]
+    fn test_dropped_byte_resync() {
+        let mut reader = FrameReader::new();
+        reader.push(&LOCK_RESPONSE[..3]);
+        reader.push(&LOCK_RESPONSE[4..]);
+        reader.push(&ACK_RESPONSE);
+        reader.push(&LOCK_RESPONSE);
+        let frames: Vec<Vec<u8>> = std::iter::from_fn(|| reader.next_frame()).collect();
+        assert_eq!(frames.last(), Some(&LOCK_RESPONSE.to_vec()));
+        assert!(frames.iter().all(|frame| *frame == ACK_RESPONSE || *frame == LOCK_RESPONSE));
+    }
+}
+
+#[cfg(test)]
+mod test_frame {
+    use super::*;
+
+    fn test_round_trip(test_data: &[u8], expected: Frame) {
+        let frame = Frame::decode(test_data).unwrap();
+        assert_eq!(frame, expected);
+        assert_eq!(frame.command(), u16::from_be_bytes([test_data[1], test_data[2]]));
+        assert_eq!(frame.encode().unwrap(), test_data);
+    }
+
+    #[test]
+    fn test_decode_responses() {
+        test_round_trip(&[0x02, 0x40, 0x80, 0x01, 0x10, 0xd1], Frame::BootConfirmResponse(BootConfirmResponse{}));
+        test_round_trip(&[0x02, 0xa0, 0xf9, 0x01, 0x00, 0x58], Frame::LockResponse(LockResponse{}));
+        test_round_trip(&[0x02, 0x40, 0x22, 0x01, 0x00, 0x63], Frame::UpdateTimeAckResponse(UpdateTimeAckResponse{}));
+        test_round_trip(&[0x02, 0x40, 0x03, 0x01, 0x00, 0x42], Frame::HandshakeResponse(HandshakeResponse{}));
+        test_round_trip(&[0x02, 0x40, 0x24, 0x01, 0x00, 0x65], Frame::AckResponse(AckResponse{}));
+        test_round_trip(&[0x02, 0x40, 0x23, 0x01, 0x00, 0x62], Frame::ScheduleResponse(ScheduleResponse{}));
+        test_round_trip(&[0x02, 0x40, 0xa2, 0x03, 0x01, 0x02, 0x00, 0xe2],
+                        Frame::UpdateTimeResponse(UpdateTimeResponse { network_id: 0x0102 }));
+        test_round_trip(&[0x02, 0xa0, 0x13, 0x0b, 0x01, 0x02, 0x01, 0x02,
+                          0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x01, 0xb2],
+                        Frame::BroadcastResponse(BroadcastResponse {
+                            network_id: 0x0102,
+                            device_id: 0x0102030405060708,
+                            data: 0x01,
+                        }));
+    }
+
+    #[test]
+    fn test_decode_requests_sharing_commands() {
+        test_round_trip(&[0x02, 0x40, 0x04, 0x00, 0x44], Frame::BootRequest(BootRequest{}));
+        test_round_trip(&[0x02, 0x40, 0x00, 0x00, 0x40], Frame::BootConfirmRequest(BootConfirmRequest{}));
+        test_round_trip(&[0x02, 0xa2, 0x36, 0x04, 0xfc, 0xff, 0x90, 0x01, 0x02], Frame::UnlockRequest(UnlockRequest{}));
+        test_round_trip(&[0x02, 0xa2, 0x36, 0x04, 0xfc, 0xff, 0x00, 0x01, 0x92], Frame::LockRequest(LockRequest{}));
+        test_round_trip(&[0x02, 0x40, 0x03, 0x04, 0x00, 0x01, 0x05, 0x00, 0x43],
+                        Frame::HandshakeRequest(HandshakeRequest { network_id: 0x0001 }));
+        test_round_trip(&[0x02, 0x40, 0x24, 0x06, 0x00, 0x02, 0x00, 0x01, 0x0a, 0x00, 0x6b],
+                        Frame::SamplesRequest(SamplesRequest { network_id: 0x0002, channel_id: 0x0001 }));
+    }
+
+    #[test]
+    fn test_decode_unknown() {
+        let mut test_data = vec![0x02, 0x12, 0x34, 0x02, 0xab, 0xcd];
+        test_data.push(message_checksum(&test_data[1..]));
+        test_round_trip(&test_data, Frame::Unknown { command: 0x1234, payload: vec![0xab, 0xcd] });
+
+        // A known command whose fixed payload doesn't match is kept, not dropped.
+        let mut test_data = vec![0x02, 0xa2, 0x36, 0x04, 0xfc, 0xff, 0x42, 0x01];
+        test_data.push(message_checksum(&test_data[1..]));
+        test_round_trip(&test_data, Frame::Unknown { command: 0xa236, payload: vec![0xfc, 0xff, 0x42, 0x01] });
+    }
+
+    #[test]
+    fn test_decode_failures() {
+        assert!(matches!(Frame::decode(&[0x02, 0x40, 0x24]), Err(DongleError::Truncated)));
+        assert!(matches!(Frame::decode(&[0x03, 0x40, 0x24, 0x01, 0x00, 0x65]), Err(DongleError::MalformedMessage(_))));
+        assert!(matches!(Frame::decode(&[0x02, 0x40, 0x24, 0x02, 0x00, 0x65]), Err(DongleError::Truncated)));
+        assert!(matches!(Frame::decode(&[0x02, 0x40, 0x24, 0x01, 0x00, 0x66]),
+                         Err(DongleError::ChecksumMismatch { expected: 0x65, actual: 0x66 })));
+    }
+
+    #[test]
+    fn test_decode_message() {
+        let test_data = [0x02, 0x40, 0x24, 0x01, 0x00, 0x65];
+        assert_eq!(decode_message::<AckResponse>(&test_data).unwrap(), AckResponse{});
+        assert!(matches!(decode_message::<LockResponse>(&test_data),
+                         Err(DongleError::UnexpectedCommand { expected: 0xa0f9, got: 0x4024 })));
+        assert!(matches!(decode_message::<AckResponse>(&test_data[..5]), Err(DongleError::Truncated)));
+    }
+}
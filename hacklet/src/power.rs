@@ -0,0 +1,180 @@
+use std::time::Duration;
+use std::time::SystemTime;
+
+use crate::messages::SamplesResponse;
+
+// The original hacklet divides raw samples by 13 to get watts. Where the
+// number comes from is unknown, but it tracks known loads closely.
+pub const WATTS_DIVISOR: f64 = 13.0;
+
+// Time between consecutive samples in one response.
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PowerReading {
+    pub timestamp: SystemTime,
+    pub raw: u16,
+    pub watts: f64,
+}
+
+impl PowerReading {
+    pub fn new(timestamp: SystemTime, raw: u16) -> PowerReading {
+        PowerReading {
+            timestamp,
+            raw,
+            watts: raw as f64 / WATTS_DIVISOR,
+        }
+    }
+}
+
+// One SamplesResponse worth of readings. The response time is when the first
+// sample was taken, and every following sample is SAMPLE_INTERVAL later.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SampleBatch {
+    pub network_id: u16,
+    pub channel_id: u16,
+    pub time: SystemTime,
+    pub stored_sample_count: u32,
+    pub readings: Vec<PowerReading>,
+}
+
+impl From<&SamplesResponse> for SampleBatch {
+    fn from(response: &SamplesResponse) -> Self {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(response.time as u64);
+        let readings = response.samples.iter()
+            .enumerate()
+            .map(|(index, sample)| PowerReading::new(time + SAMPLE_INTERVAL * index as u32, *sample))
+            .collect();
+        let [low, middle, high] = response.stored_sample_count;
+
+        SampleBatch {
+            network_id: response.network_id,
+            channel_id: response.channel_id,
+            time,
+            stored_sample_count: u32::from_le_bytes([low, middle, high, 0]),
+            readings,
+        }
+    }
+}
+
+impl SampleBatch {
+    // Modlets report times on their own clock, which is ahead of UTC by
+    // whatever offset it was set with.
+    pub fn to_utc(mut self, utc_offset: i32) -> SampleBatch {
+        let shift = |time: SystemTime| match utc_offset {
+            offset if offset >= 0 => time - Duration::from_secs(offset as u64),
+            offset => time + Duration::from_secs(offset.unsigned_abs() as u64),
+        };
+        self.time = shift(self.time);
+        for reading in &mut self.readings {
+            reading.timestamp = shift(reading.timestamp);
+        }
+        self
+    }
+}
+
+// A stretch between two consecutive readings where samples are missing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Gap {
+    pub after: SystemTime,
+    pub before: SystemTime,
+}
+
+impl Gap {
+    pub fn duration(&self) -> Duration {
+        self.before.duration_since(self.after).unwrap_or_default()
+    }
+}
+
+// Readings from any number of batches, oldest first with duplicates removed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct History {
+    pub readings: Vec<PowerReading>,
+    pub gaps: Vec<Gap>,
+}
+
+impl History {
+    pub fn from_batches<'a, I>(batches: I) -> History
+    where
+        I: IntoIterator<Item = &'a SampleBatch>
+    {
+        let mut readings: Vec<PowerReading> = batches.into_iter()
+            .flat_map(|batch| batch.readings.iter().cloned())
+            .collect();
+        readings.sort_by_key(|reading| reading.timestamp);
+        readings.dedup_by_key(|reading| reading.timestamp);
+
+        // Batches don't necessarily line up on the same ten second grid, so
+        // allow some slack before calling it a gap.
+        let tolerance = SAMPLE_INTERVAL + SAMPLE_INTERVAL / 2;
+        let gaps = readings.windows(2)
+            .filter(|pair| pair[1].timestamp.duration_since(pair[0].timestamp).unwrap_or_default() > tolerance)
+            .map(|pair| Gap { after: pair[0].timestamp, before: pair[1].timestamp })
+            .collect();
+
+        History { readings, gaps }
+    }
+}
+
+#[cfg(test)]
+mod test_power {
+    use super::*;
+
+    #[test]
+    fn test_batch_from_response() {
+        let response = SamplesResponse {
+            network_id: 0x0102,
+            channel_id: 0x0001,
+            data: 0x0102,
+            time: 1_700_000_000,
+            sample_count: 3,
+            stored_sample_count: [0x2c, 0x01, 0x00],
+            samples: vec![0, 130, 1300],
+        };
+        let batch = SampleBatch::from(&response);
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        assert_eq!(batch.time, start);
+        assert_eq!(batch.stored_sample_count, 300);
+        assert_eq!(batch.readings, vec![
+            PowerReading { timestamp: start, raw: 0, watts: 0.0 },
+            PowerReading { timestamp: start + Duration::from_secs(10), raw: 130, watts: 10.0 },
+            PowerReading { timestamp: start + Duration::from_secs(20), raw: 1300, watts: 100.0 },
+        ]);
+    }
+
+    fn batch_at(seconds: u64, samples: &[u16]) -> SampleBatch {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(seconds);
+        SampleBatch {
+            network_id: 0x0102,
+            channel_id: 0,
+            time,
+            stored_sample_count: 0,
+            readings: samples.iter()
+                .enumerate()
+                .map(|(index, sample)| PowerReading::new(time + SAMPLE_INTERVAL * index as u32, *sample))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_history_orders_and_deduplicates() {
+        let batches = [batch_at(1030, &[4, 5, 6]), batch_at(1000, &[1, 2, 3, 4])];
+        let history = History::from_batches(&batches);
+        let raw: Vec<u16> = history.readings.iter().map(|reading| reading.raw).collect();
+        assert_eq!(raw, vec![1, 2, 3, 4, 5, 6]);
+        assert!(history.gaps.is_empty());
+    }
+
+    #[test]
+    fn test_history_reports_gaps() {
+        let batches = [batch_at(1000, &[1, 2]), batch_at(1100, &[3, 4])];
+        let history = History::from_batches(&batches);
+        assert_eq!(history.readings.len(), 4);
+        assert_eq!(history.gaps, vec![Gap {
+            after: SystemTime::UNIX_EPOCH + Duration::from_secs(1010),
+            before: SystemTime::UNIX_EPOCH + Duration::from_secs(1100),
+        }]);
+        assert_eq!(history.gaps[0].duration(), Duration::from_secs(90));
+    }
+}
@@ -0,0 +1,423 @@
+use std::str::FromStr;
+
+use crate::error::DongleError;
+
+// Our best understanding of the 56 byte ScheduleRequest bitmap: seven days of
+// eight entries each, starting on Sunday. Every entry is one switching event
+// for that day, with the top bit being the state the outlet switches to and
+// the bottom seven bits the time of day in 15 minute slots. A slot of 0x7f
+// marks an unused entry. The only bitmaps ever captured from the original
+// software are the always on and always off programs, which are reproduced
+// exactly for constant schedules.
+pub const SCHEDULE_LENGTH: usize = 56;
+pub const SLOT_MINUTES: u16 = 15;
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+const SLOTS_PER_DAY: usize = (MINUTES_PER_DAY / SLOT_MINUTES) as usize;
+const SLOTS_PER_WEEK: usize = SLOTS_PER_DAY * 7;
+const ENTRIES_PER_DAY: usize = SCHEDULE_LENGTH / 7;
+const STATE_ON: u8 = 0x80;
+const UNUSED_SLOT: u8 = 0x7f;
+
+// Where the original software puts the single switching event of a constant
+// schedule, and the slot it uses.
+const CONSTANT_ENTRY: usize = 5;
+const CONSTANT_SLOT: u8 = 0x25;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Weekday {
+    Sunday,
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+}
+
+impl Weekday {
+    pub const ALL: [Weekday; 7] = [
+        Weekday::Sunday,
+        Weekday::Monday,
+        Weekday::Tuesday,
+        Weekday::Wednesday,
+        Weekday::Thursday,
+        Weekday::Friday,
+        Weekday::Saturday,
+    ];
+
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+
+    pub fn short_name(&self) -> &'static str {
+        match self {
+            Weekday::Sunday => "Sun",
+            Weekday::Monday => "Mon",
+            Weekday::Tuesday => "Tue",
+            Weekday::Wednesday => "Wed",
+            Weekday::Thursday => "Thu",
+            Weekday::Friday => "Fri",
+            Weekday::Saturday => "Sat",
+        }
+    }
+
+    pub fn next(&self) -> Weekday {
+        Weekday::ALL[(self.index() + 1) % 7]
+    }
+}
+
+impl FromStr for Weekday {
+    type Err = DongleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.trim().to_ascii_lowercase();
+        Weekday::ALL.into_iter()
+            .find(|day| name.len() >= 3 && format!("{:?}", day).to_ascii_lowercase().starts_with(&name))
+            .ok_or_else(|| DongleError::InvalidSchedule(format!("{:?} is not a day of the week", s.trim())))
+    }
+}
+
+// A span of time within one day during which the outlet is on, in minutes
+// since midnight. The end is exclusive and may be 24:00.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Interval {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl Interval {
+    pub fn new(start: u16, end: u16) -> Result<Interval, DongleError> {
+        if start >= end || end > MINUTES_PER_DAY {
+            return Err(DongleError::InvalidSchedule(format!("{} is not a valid interval within one day", Interval { start, end })));
+        }
+        if !start.is_multiple_of(SLOT_MINUTES) || !end.is_multiple_of(SLOT_MINUTES) {
+            return Err(DongleError::InvalidSchedule(format!("{} does not fall on {} minute boundaries", Interval { start, end }, SLOT_MINUTES)));
+        }
+        Ok(Interval { start, end })
+    }
+}
+
+fn parse_time(s: &str) -> Result<u16, DongleError> {
+    let invalid = || DongleError::InvalidSchedule(format!("{:?} is not a time of day (HH:MM)", s));
+    let (hours, minutes) = s.trim().split_once(':').ok_or_else(invalid)?;
+    let hours: u16 = hours.parse().map_err(|_| invalid())?;
+    let minutes: u16 = minutes.parse().map_err(|_| invalid())?;
+    if minutes >= 60 || hours > 24 || hours * 60 + minutes > MINUTES_PER_DAY {
+        return Err(invalid());
+    }
+    Ok(hours * 60 + minutes)
+}
+
+impl std::fmt::Display for Interval {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:02}:{:02}-{:02}:{:02}", self.start / 60, self.start % 60, self.end / 60, self.end % 60)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WeeklySchedule {
+    slots: [[bool; SLOTS_PER_DAY]; 7],
+}
+
+impl WeeklySchedule {
+    pub fn always_on() -> WeeklySchedule {
+        WeeklySchedule { slots: [[true; SLOTS_PER_DAY]; 7] }
+    }
+
+    pub fn always_off() -> WeeklySchedule {
+        WeeklySchedule { slots: [[false; SLOTS_PER_DAY]; 7] }
+    }
+
+    pub fn from_intervals<I>(intervals: I) -> Result<WeeklySchedule, DongleError>
+    where
+        I: IntoIterator<Item = (Weekday, Interval)>
+    {
+        let mut schedule = WeeklySchedule::always_off();
+        for (day, interval) in intervals {
+            let interval = Interval::new(interval.start, interval.end)?;
+            let start = (interval.start / SLOT_MINUTES) as usize;
+            let end = (interval.end / SLOT_MINUTES) as usize;
+            schedule.slots[day.index()][start..end].fill(true);
+        }
+        Ok(schedule)
+    }
+
+    // The on intervals for one day, merged where they touch.
+    pub fn intervals(&self, day: Weekday) -> Vec<Interval> {
+        let slots = &self.slots[day.index()];
+        let mut intervals = Vec::new();
+        let mut slot = 0;
+        while slot < SLOTS_PER_DAY {
+            if !slots[slot] {
+                slot += 1;
+                continue;
+            }
+            let start = slot;
+            while slot < SLOTS_PER_DAY && slots[slot] {
+                slot += 1;
+            }
+            intervals.push(Interval {
+                start: start as u16 * SLOT_MINUTES,
+                end: slot as u16 * SLOT_MINUTES,
+            });
+        }
+        intervals
+    }
+
+    pub fn is_on(&self, day: Weekday, minute: u16) -> bool {
+        let slot = (minute.min(MINUTES_PER_DAY - 1) / SLOT_MINUTES) as usize;
+        self.slots[day.index()][slot]
+    }
+
+    // Always on or always off, the only programs whose encoding has been
+    // checked against the original software.
+    pub fn is_constant(&self) -> bool {
+        self.slots.iter().flatten().all(|slot| *slot == self.slots[0][0])
+    }
+
+    fn slot(&self, week_slot: usize) -> bool {
+        self.slots[week_slot / SLOTS_PER_DAY][week_slot % SLOTS_PER_DAY]
+    }
+
+    // One row per day with a column per 15 minute slot, '#' for on and '.'
+    // for off.
+    pub fn render_grid(&self) -> String {
+        let mut grid = String::from("    ");
+        for hour in 0..24 {
+            grid.push_str(&format!("{:<4}", format!("{:02}", hour)));
+        }
+        grid.push('\n');
+        for day in Weekday::ALL {
+            grid.push_str(day.short_name());
+            grid.push(' ');
+            for slot in self.slots[day.index()] {
+                grid.push(if slot { '#' } else { '.' });
+            }
+            grid.push('\n');
+        }
+        grid
+    }
+
+    pub fn encode(&self) -> Result<[u8; SCHEDULE_LENGTH], DongleError> {
+        let transitions: Vec<usize> = (0..SLOTS_PER_WEEK)
+            .filter(|slot| self.slot(*slot) != self.slot((slot + SLOTS_PER_WEEK - 1) % SLOTS_PER_WEEK))
+            .collect();
+
+        if transitions.is_empty() {
+            let state = if self.slot(0) { STATE_ON } else { 0 };
+            let mut bitmap = [UNUSED_SLOT | state; SCHEDULE_LENGTH];
+            bitmap[CONSTANT_ENTRY] = CONSTANT_SLOT | state;
+            return Ok(bitmap);
+        }
+
+        let mut bitmap = [0u8; SCHEDULE_LENGTH];
+        for day in Weekday::ALL {
+            let first_slot = day.index() * SLOTS_PER_DAY;
+            let day_transitions: Vec<usize> = transitions.iter()
+                .copied()
+                .filter(|slot| (first_slot..first_slot + SLOTS_PER_DAY).contains(slot))
+                .collect();
+            if day_transitions.len() > ENTRIES_PER_DAY {
+                return Err(DongleError::InvalidSchedule(format!("{} switches {} times, at most {} are supported",
+                                                                day.short_name(), day_transitions.len(), ENTRIES_PER_DAY)));
+            }
+
+            let entries = &mut bitmap[day.index() * ENTRIES_PER_DAY..(day.index() + 1) * ENTRIES_PER_DAY];
+            let end_state = if self.slot(first_slot + SLOTS_PER_DAY - 1) { STATE_ON } else { 0 };
+            entries.fill(UNUSED_SLOT | end_state);
+            for (entry, slot) in entries.iter_mut().zip(day_transitions) {
+                let state = if self.slot(slot) { STATE_ON } else { 0 };
+                *entry = state | (slot - first_slot) as u8;
+            }
+        }
+        Ok(bitmap)
+    }
+
+    pub fn decode(bitmap: &[u8; SCHEDULE_LENGTH]) -> WeeklySchedule {
+        let mut transitions: Vec<(usize, bool)> = bitmap.iter()
+            .enumerate()
+            .filter(|(_, entry)| ((*entry & !STATE_ON) as usize) < SLOTS_PER_DAY)
+            .map(|(index, entry)| {
+                let day = index / ENTRIES_PER_DAY;
+                let slot = (entry & !STATE_ON) as usize;
+                (day * SLOTS_PER_DAY + slot, entry & STATE_ON != 0)
+            })
+            .collect();
+        transitions.sort_by_key(|(slot, _)| *slot);
+
+        let mut state = match transitions.last() {
+            Some((_, state)) => *state,
+            None => bitmap[0] & STATE_ON != 0,
+        };
+        let mut schedule = WeeklySchedule::always_off();
+        let mut pending = transitions.iter().peekable();
+        for week_slot in 0..SLOTS_PER_WEEK {
+            while let Some((_, new_state)) = pending.next_if(|(slot, _)| *slot == week_slot) {
+                state = *new_state;
+            }
+            schedule.slots[week_slot / SLOTS_PER_DAY][week_slot % SLOTS_PER_DAY] = state;
+        }
+        schedule
+    }
+}
+
+// Parses programs like "Mon-Fri 07:00-09:00,18:00-23:00; Sat-Sun 09:00-23:30".
+// Each clause is a day or a range of days followed by on intervals, or "on"
+// or "off" for the whole day. An interval that ends before it starts runs
+// past midnight into the following day. Days that are not mentioned are off.
+impl FromStr for WeeklySchedule {
+    type Err = DongleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut intervals = Vec::new();
+        for clause in s.split(';').map(str::trim).filter(|clause| !clause.is_empty()) {
+            let (days, times) = clause.split_once(char::is_whitespace)
+                .ok_or_else(|| DongleError::InvalidSchedule(format!("{:?} has no times", clause)))?;
+            let days = parse_days(days)?;
+            for times in times.split(',').map(str::trim) {
+                for day in &days {
+                    match times.to_ascii_lowercase().as_str() {
+                        "on" => intervals.push((*day, Interval { start: 0, end: MINUTES_PER_DAY })),
+                        "off" => (),
+                        _ => {
+                            let (start, end) = times.split_once('-')
+                                .ok_or_else(|| DongleError::InvalidSchedule(format!("{:?} is not an interval (HH:MM-HH:MM)", times)))?;
+                            let (start, end) = (parse_time(start)?, parse_time(end)?);
+                            if end > start {
+                                intervals.push((*day, Interval::new(start, end)?));
+                            } else {
+                                intervals.push((*day, Interval::new(start, MINUTES_PER_DAY)?));
+                                if end > 0 {
+                                    intervals.push((day.next(), Interval::new(0, end)?));
+                                }
+                            }
+                        },
+                    }
+                }
+            }
+        }
+        WeeklySchedule::from_intervals(intervals)
+    }
+}
+
+fn parse_days(days: &str) -> Result<Vec<Weekday>, DongleError> {
+    match days.to_ascii_lowercase().as_str() {
+        "daily" | "all" => return Ok(Weekday::ALL.to_vec()),
+        "weekdays" => return parse_days("Mon-Fri"),
+        "weekends" => return parse_days("Sat-Sun"),
+        _ => (),
+    }
+
+    let mut result = Vec::new();
+    for range in days.split(',') {
+        match range.split_once('-') {
+            Some((first, last)) => {
+                let (mut day, last) = (first.parse::<Weekday>()?, last.parse::<Weekday>()?);
+                result.push(day);
+                while day != last {
+                    day = day.next();
+                    result.push(day);
+                }
+            },
+            None => result.push(range.parse::<Weekday>()?),
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test_schedule {
+    use super::*;
+
+    fn legacy_bitmap(fill: u8, entry: u8) -> [u8; SCHEDULE_LENGTH] {
+        let mut bitmap = [fill; SCHEDULE_LENGTH];
+        bitmap[5] = entry;
+        bitmap
+    }
+
+    #[test]
+    fn test_constant_schedules_match_original() {
+        assert_eq!(WeeklySchedule::always_on().encode().unwrap(), legacy_bitmap(0xff, 0xa5));
+        assert_eq!(WeeklySchedule::always_off().encode().unwrap(), legacy_bitmap(0x7f, 0x25));
+        assert_eq!(WeeklySchedule::decode(&legacy_bitmap(0xff, 0xa5)), WeeklySchedule::always_on());
+        assert_eq!(WeeklySchedule::decode(&legacy_bitmap(0x7f, 0x25)), WeeklySchedule::always_off());
+        assert!(WeeklySchedule::always_on().is_constant());
+        assert!(WeeklySchedule::always_off().is_constant());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let morning = Interval::new(7 * 60, 9 * 60).unwrap();
+        let evening = Interval::new(18 * 60, 23 * 60 + 30).unwrap();
+        let late = Interval::new(22 * 60, 24 * 60).unwrap();
+        let early = Interval::new(0, 2 * 60).unwrap();
+        let schedule = WeeklySchedule::from_intervals([
+            (Weekday::Monday, morning),
+            (Weekday::Monday, evening),
+            (Weekday::Friday, late),
+            (Weekday::Saturday, early),
+        ]).unwrap();
+
+        assert_eq!(schedule.intervals(Weekday::Monday), vec![morning, evening]);
+        assert_eq!(schedule.intervals(Weekday::Sunday), vec![]);
+        assert!(schedule.is_on(Weekday::Saturday, 60));
+        assert!(!schedule.is_on(Weekday::Saturday, 120));
+        assert!(!schedule.is_constant());
+
+        let bitmap = schedule.encode().unwrap();
+        assert_eq!(bitmap[8..16], [0x80 | 28, 36, 0x80 | 72, 94, 0x7f, 0x7f, 0x7f, 0x7f]);
+        assert_eq!(bitmap[40..48], [0x80 | 88, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(bitmap[48..56], [8, 0x7f, 0x7f, 0x7f, 0x7f, 0x7f, 0x7f, 0x7f]);
+        assert_eq!(WeeklySchedule::decode(&bitmap), schedule);
+    }
+
+    #[test]
+    fn test_invalid_intervals() {
+        assert!(Interval::new(9 * 60, 7 * 60).is_err());
+        assert!(Interval::new(0, 24 * 60 + 15).is_err());
+        assert!(Interval::new(7 * 60 + 5, 9 * 60).is_err());
+    }
+
+    #[test]
+    fn test_too_many_transitions() {
+        let intervals = (0..5).map(|hour| (Weekday::Tuesday, Interval::new(hour * 120, hour * 120 + 60).unwrap()));
+        let schedule = WeeklySchedule::from_intervals(intervals).unwrap();
+        assert!(matches!(schedule.encode(), Err(DongleError::InvalidSchedule(_))));
+    }
+
+    #[test]
+    fn test_parse_program() {
+        let schedule: WeeklySchedule = "Mon-Fri 07:00-09:00,18:00-23:00; Sat-Sun 09:00-23:30".parse().unwrap();
+        for day in [Weekday::Monday, Weekday::Wednesday, Weekday::Friday] {
+            assert_eq!(schedule.intervals(day), vec![Interval::new(420, 540).unwrap(), Interval::new(1080, 1380).unwrap()]);
+        }
+        for day in [Weekday::Saturday, Weekday::Sunday] {
+            assert_eq!(schedule.intervals(day), vec![Interval::new(540, 1410).unwrap()]);
+        }
+
+        let overnight: WeeklySchedule = "sat 22:00-02:00; tuesday on".parse().unwrap();
+        assert_eq!(overnight.intervals(Weekday::Saturday), vec![Interval::new(1320, 1440).unwrap()]);
+        assert_eq!(overnight.intervals(Weekday::Sunday), vec![Interval::new(0, 120).unwrap()]);
+        assert_eq!(overnight.intervals(Weekday::Tuesday), vec![Interval::new(0, 1440).unwrap()]);
+
+        assert!("Mon".parse::<WeeklySchedule>().is_err());
+        assert!("Moonday 07:00-09:00".parse::<WeeklySchedule>().is_err());
+        assert!("Mon 07:00-09:10".parse::<WeeklySchedule>().is_err());
+        assert!("Mon 7-9".parse::<WeeklySchedule>().is_err());
+        assert!("Mon 1100:00-02:00".parse::<WeeklySchedule>().is_err());
+        assert!("Mon 16384:00-02:00".parse::<WeeklySchedule>().is_err());
+        assert!("Mon 00:00-24:30".parse::<WeeklySchedule>().is_err());
+    }
+
+    #[test]
+    fn test_render_grid() {
+        let schedule: WeeklySchedule = "Sun 00:00-01:00".parse().unwrap();
+        let grid = schedule.render_grid();
+        let lines: Vec<&str> = grid.lines().collect();
+        assert_eq!(lines.len(), 8);
+        assert!(lines[0].starts_with("    00  01  02"));
+        assert_eq!(lines[1], format!("Sun {}{}", "#".repeat(4), ".".repeat(92)));
+        assert_eq!(lines[7], format!("Sat {}", ".".repeat(96)));
+    }
+}
@@ -1,59 +1,76 @@
 use log::{debug, error, trace};
 use std::time::Duration;
+use std::time::Instant;
 
 use libftd2xx::BitMode;
+use libftd2xx::DeviceInfo;
 use libftd2xx::Ftdi;
 use libftd2xx::FtStatus;
 use libftd2xx::FtdiCommon;
 
+use crate::error::DongleError;
+use crate::transport::SerialSettings;
+use crate::transport::Transport;
+
 pub struct SerialConnection {
     pub connection: Ftdi,
+    write_timeout: Duration,
 }
 
 impl SerialConnection {
-    pub fn new() -> Result<SerialConnection, FtStatus> {
-        let mut ftd = SerialConnection::usb_open(0x0403, 0x8c81)?;
-        ftd.set_bit_mode(0x00, BitMode::Reset)?;
-        ftd.set_baud_rate(115200)?;
-        ftd.set_flow_control_none()?;
-        ftd.set_dtr()?;
-        ftd.set_rts()?;
-        ftd.set_timeouts(Duration::from_secs(30), Duration::from_secs(5))?;
-        
-        let rx_bytes = ftd.queue_status()?;
-        if rx_bytes != 0 {
-            let _ = ftd.purge_rx();
-        }
+    pub fn new(settings: &SerialSettings) -> Result<SerialConnection, FtStatus> {
+        let ftd = SerialConnection::usb_open(settings.vendor_id, settings.product_id)?;
+        SerialConnection::configure(ftd, settings)
+    }
 
-        Ok(SerialConnection {
-            connection: ftd,
-        })
+    pub fn with_serial_number(serial_number: &str, settings: &SerialSettings) -> Result<SerialConnection, FtStatus> {
+        debug!("Opening USB device {:?}", serial_number);
+        libftd2xx::set_vid_pid(settings.vendor_id, settings.product_id)?;
+        SerialConnection::configure(Ftdi::with_serial_number(serial_number)?, settings)
     }
 
-    pub fn close(&mut self) {
-        debug!("Closing serial connection");
-        let _ = self.connection.close();
+    // Counts Modlet dongles only, in the order list_dongles returns them.
+    pub fn with_index(index: usize, settings: &SerialSettings) -> Result<SerialConnection, FtStatus> {
+        let dongles = SerialConnection::list_dongles(settings)?;
+        let device = dongles.get(index).ok_or(FtStatus::DEVICE_NOT_FOUND)?;
+        SerialConnection::with_serial_number(&device.serial_number, settings)
     }
 
-    pub fn transmit(&mut self, command: &[u8]) -> Result<usize, FtStatus> {
-        trace!("TX: {:x?}", command);
-        self.connection.write(command)
+    // Every FTDI device the driver can see, Modlet dongles or not.
+    pub fn list_devices(settings: &SerialSettings) -> Result<Vec<DeviceInfo>, FtStatus> {
+        libftd2xx::set_vid_pid(settings.vendor_id, settings.product_id)?;
+        libftd2xx::list_devices()
     }
 
-    pub fn receive(&mut self, expected_bytes: usize) -> Result<Vec<u8>, FtStatus> {
-        let mut bytes = vec![0u8; expected_bytes];
-        let mut bytes_read: usize = 0;
-        loop {
-            let rx_bytes = self.connection.queue_status()?;
-            if rx_bytes >= 1 {
-                let bytes_to_read = std::cmp::min(rx_bytes, expected_bytes-bytes_read);
-                bytes_read += self.connection.read( &mut bytes[bytes_read..bytes_read+bytes_to_read])?;
-                if bytes_read == expected_bytes {
-                    trace!("RX: {:x?}", bytes);
-                    return Ok(bytes);
-                }
+    pub fn list_dongles(settings: &SerialSettings) -> Result<Vec<DeviceInfo>, FtStatus> {
+        let devices = SerialConnection::list_devices(settings)?;
+        Ok(devices.into_iter().filter(|device| SerialConnection::is_dongle(device, settings)).collect())
+    }
+
+    pub fn is_dongle(device: &DeviceInfo, settings: &SerialSettings) -> bool {
+        device.vendor_id == settings.vendor_id && device.product_id == settings.product_id
+    }
+
+    fn configure(mut ftd: Ftdi, settings: &SerialSettings) -> Result<SerialConnection, FtStatus> {
+        ftd.set_bit_mode(0x00, BitMode::Reset)?;
+        ftd.set_baud_rate(settings.baud_rate)?;
+        ftd.set_flow_control_none()?;
+        ftd.set_dtr()?;
+        ftd.set_rts()?;
+        // receive replaces the read timeout with what is left of its deadline.
+        ftd.set_timeouts(Duration::from_secs(30), driver_timeout(settings.write_timeout))?;
+
+        if settings.purge {
+            let rx_bytes = ftd.queue_status()?;
+            if rx_bytes != 0 {
+                let _ = ftd.purge_rx();
             }
         }
+
+        Ok(SerialConnection {
+            connection: ftd,
+            write_timeout: settings.write_timeout,
+        })
     }
 
     fn usb_open(vendor: u16, product: u16) -> Result<Ftdi, FtStatus> {
@@ -94,9 +111,74 @@ impl SerialConnection {
     }
 }
 
+impl Transport for SerialConnection {
+    fn close(&mut self) {
+        debug!("Closing serial connection");
+        let _ = self.connection.close();
+    }
+
+    fn transmit(&mut self, command: &[u8]) -> Result<usize, DongleError> {
+        trace!("TX: {:x?}", command);
+        Ok(self.connection.write(command)?)
+    }
+
+    fn receive(&mut self, max_bytes: usize, deadline: Instant) -> Result<Vec<u8>, DongleError> {
+        let mut bytes = vec![0u8; max_bytes];
+        loop {
+            let rx_bytes = self.connection.queue_status()?;
+            if rx_bytes >= 1 {
+                let bytes_to_read = std::cmp::min(rx_bytes, max_bytes);
+                let bytes_read = self.connection.read(&mut bytes[..bytes_to_read])?;
+                bytes.truncate(bytes_read);
+                trace!("RX: {:x?}", bytes);
+                return Ok(bytes);
+            }
+
+            // The driver counts in whole milliseconds and takes 0 to mean
+            // no timeout at all, so give up once less than one is left.
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining < Duration::from_millis(1) {
+                return Err(DongleError::Timeout);
+            }
+
+            // Let the driver block until the first byte shows up instead of
+            // spinning on the queue status, then pick up the rest of the
+            // queue on the next pass.
+            self.connection.set_timeouts(driver_timeout(remaining), driver_timeout(self.write_timeout))?;
+            if self.connection.read(&mut bytes[..1])? == 1 {
+                let rx_bytes = self.connection.queue_status()?;
+                let bytes_to_read = std::cmp::min(rx_bytes, max_bytes - 1);
+                let bytes_read = self.connection.read(&mut bytes[1..1+bytes_to_read])?;
+                bytes.truncate(1 + bytes_read);
+                trace!("RX: {:x?}", bytes);
+                return Ok(bytes);
+            }
+        }
+    }
+}
+
+// Rounds up to the next millisecond and caps at the largest timeout the
+// driver takes, which it would otherwise panic on.
+fn driver_timeout(timeout: Duration) -> Duration {
+    let millis = timeout.as_nanos().div_ceil(1_000_000);
+    Duration::from_millis(millis.min(u32::MAX as u128) as u64)
+}
+
 impl Drop for SerialConnection {
     fn drop(&mut self) {
         debug!("Dropping serial connection");
         self.close();
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test_serial_connection {
+    use super::*;
+
+    #[test]
+    fn test_driver_timeout() {
+        assert_eq!(driver_timeout(Duration::from_micros(1)), Duration::from_millis(1));
+        assert_eq!(driver_timeout(Duration::from_micros(1500)), Duration::from_millis(2));
+        assert_eq!(driver_timeout(Duration::from_millis(250)), Duration::from_millis(250));
+        assert_eq!(driver_timeout(Duration::MAX), Duration::from_millis(u32::MAX as u64));
+    }
+}
@@ -0,0 +1,45 @@
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::error::DongleError;
+
+// Anything that can carry the Modlet serial protocol. The dongle logic only
+// ever needs to push bytes out, pull bytes back in, and release the
+// underlying device when it is done.
+pub trait Transport {
+    fn transmit(&mut self, data: &[u8]) -> Result<usize, DongleError>;
+
+    // Block until at least one byte has arrived, then return whatever is
+    // available up to max_bytes. Returns DongleError::Timeout if nothing
+    // arrives before the deadline.
+    fn receive(&mut self, max_bytes: usize, deadline: Instant) -> Result<Vec<u8>, DongleError>;
+
+    fn close(&mut self);
+}
+
+// How to find and set up the serial link. The defaults match the original
+// ThinkEco dongle. Backends that can't apply a setting ignore it: a tty has
+// no USB IDs and its writes simply block until done.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SerialSettings {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub baud_rate: u32,
+    // Reads wait for as long as the request allows, so there is no read
+    // timeout to set.
+    pub write_timeout: Duration,
+    // Throw away anything already waiting in the receive buffer on open
+    pub purge: bool,
+}
+
+impl Default for SerialSettings {
+    fn default() -> Self {
+        SerialSettings {
+            vendor_id: 0x0403,
+            product_id: 0x8c81,
+            baud_rate: 115200,
+            write_timeout: Duration::from_secs(5),
+            purge: true,
+        }
+    }
+}
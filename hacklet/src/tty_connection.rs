@@ -0,0 +1,123 @@
+use log::{debug, trace};
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::io::Write;
+use std::os::fd::AsFd;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::Instant;
+
+use nix::errno::Errno;
+use nix::libc;
+use nix::poll::PollFd;
+use nix::poll::PollFlags;
+use nix::poll::PollTimeout;
+use nix::sys::termios;
+use nix::sys::termios::BaudRate;
+use nix::sys::termios::ControlFlags;
+use nix::sys::termios::FlushArg;
+use nix::sys::termios::InputFlags;
+use nix::sys::termios::SetArg;
+use nix::sys::termios::SpecialCharacterIndices;
+
+use crate::error::DongleError;
+use crate::transport::SerialSettings;
+use crate::transport::Transport;
+
+nix::ioctl_write_ptr_bad!(tiocmbis, libc::TIOCMBIS, libc::c_int);
+
+fn baud_rate(rate: u32) -> Result<BaudRate, DongleError> {
+    match rate {
+        9600 => Ok(BaudRate::B9600),
+        19200 => Ok(BaudRate::B19200),
+        38400 => Ok(BaudRate::B38400),
+        57600 => Ok(BaudRate::B57600),
+        115200 => Ok(BaudRate::B115200),
+        230400 => Ok(BaudRate::B230400),
+        _ => Err(DongleError::Unsupported(format!("{} baud is not supported on a tty", rate))),
+    }
+}
+
+// Talks to the dongle through a tty created by the kernel ftdi_sio driver,
+// e.g. /dev/ttyUSB0, configured to match what SerialConnection asks of D2XX.
+pub struct TtyConnection {
+    pub port: File,
+}
+
+impl TtyConnection {
+    pub fn new(path: &Path, serial: &SerialSettings) -> Result<TtyConnection, DongleError> {
+        debug!("Opening tty {:?}", path);
+        let port = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(path)?;
+
+        let mut settings = termios::tcgetattr(&port)?;
+        termios::cfmakeraw(&mut settings);
+        termios::cfsetspeed(&mut settings, baud_rate(serial.baud_rate)?)?;
+        settings.control_flags &= !(ControlFlags::PARENB | ControlFlags::CSTOPB | ControlFlags::CRTSCTS);
+        settings.control_flags |= ControlFlags::CS8 | ControlFlags::CLOCAL | ControlFlags::CREAD;
+        settings.input_flags &= !(InputFlags::IXON | InputFlags::IXOFF | InputFlags::IXANY);
+        settings.control_chars[SpecialCharacterIndices::VMIN as usize] = 1;
+        settings.control_chars[SpecialCharacterIndices::VTIME as usize] = 0;
+        termios::tcsetattr(&port, SetArg::TCSANOW, &settings)?;
+
+        let lines: libc::c_int = libc::TIOCM_DTR | libc::TIOCM_RTS;
+        // Pseudo-terminals such as hacklet-sim have no modem control lines.
+        match unsafe { tiocmbis(port.as_raw_fd(), &lines) } {
+            Ok(_) | Err(Errno::ENOTTY) => (),
+            Err(err) => return Err(err.into()),
+        }
+
+        if serial.purge {
+            termios::tcflush(&port, FlushArg::TCIFLUSH)?;
+        }
+        trace!("Configured tty");
+
+        Ok(TtyConnection {
+            port,
+        })
+    }
+}
+
+impl Transport for TtyConnection {
+    fn close(&mut self) {
+        debug!("Closing tty connection");
+        let _ = self.port.flush();
+    }
+
+    fn transmit(&mut self, command: &[u8]) -> Result<usize, DongleError> {
+        trace!("TX: {:x?}", command);
+        self.port.write_all(command)?;
+        Ok(command.len())
+    }
+
+    fn receive(&mut self, max_bytes: usize, deadline: Instant) -> Result<Vec<u8>, DongleError> {
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(DongleError::Timeout);
+            }
+
+            let timeout = PollTimeout::try_from(remaining).unwrap_or(PollTimeout::MAX);
+            let mut fds = [PollFd::new(self.port.as_fd(), PollFlags::POLLIN)];
+            match nix::poll::poll(&mut fds, timeout) {
+                Ok(0) | Err(Errno::EINTR) => continue,
+                Ok(_) => (),
+                Err(err) => return Err(err.into()),
+            }
+
+            let mut bytes = vec![0u8; max_bytes];
+            let bytes_read = self.port.read(&mut bytes)?;
+            if bytes_read == 0 {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+            bytes.truncate(bytes_read);
+            trace!("RX: {:x?}", bytes);
+            return Ok(bytes);
+        }
+    }
+}